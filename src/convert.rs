@@ -0,0 +1,825 @@
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    num::{IntErrorKind, ParseIntError},
+    str::FromStr,
+};
+
+use chrono::{DateTime, NaiveDate, NaiveDateTime, TimeZone, Utc};
+use chrono_tz::Tz;
+use primitive_types::U256;
+use thiserror::Error;
+
+/// Integers with an absolute value above this are read as unix milliseconds
+/// instead of unix seconds (it is year 5138 in seconds)
+const UNIX_MILLIS_THRESHOLD: i64 = 100_000_000_000;
+
+#[derive(Error, Debug, PartialEq)]
+pub enum ConversionError {
+    #[error("missing value for a non nullable column")]
+    MissingValue,
+    #[error("unsupported column type {0}")]
+    UnsupportedType(String),
+    #[error("invalid boolean value {0:?}")]
+    InvalidBool(String),
+    #[error("invalid float value {0:?}")]
+    InvalidFloat(String),
+    #[error("invalid decimal value {0:?}")]
+    InvalidDecimal(String),
+    #[error("decimal value {0:?} does not fit in Decimal({1}, {2})")]
+    DecimalOutOfRange(String, u32, u32),
+    #[error("invalid integer value {0:?}")]
+    InvalidInteger(String),
+    #[error("integer value {0:?} is out of range for {1}")]
+    IntegerOutOfRange(String, &'static str),
+    #[error("invalid array value {0:?}")]
+    InvalidArray(String),
+    #[error("invalid map value {0:?}")]
+    InvalidMap(String),
+    #[error("invalid tuple value {0:?}")]
+    InvalidTuple(String),
+    #[error("invalid UUID value {0:?}")]
+    InvalidUuid(String),
+    #[error("invalid IP address {0:?}")]
+    InvalidIpAddress(String),
+    #[error("value {0:?} is not part of the enum")]
+    InvalidEnum(String),
+    #[error("invalid hex value {0:?}")]
+    InvalidHex(String),
+    #[error("value {0:?} is longer than FixedString({1})")]
+    FixedStringOverflow(String, usize),
+    #[error("invalid date time value {0:?}")]
+    InvalidDateTime(String),
+    #[error("unknown timezone {0:?}")]
+    InvalidTimezone(String),
+    #[error("date time value {0:?} is out of range for {1}")]
+    DateTimeOutOfRange(String, &'static str),
+}
+
+/// Parses an integer that fits in the column type `type_name`
+pub fn parse_integer<T>(value: &str, type_name: &'static str) -> Result<T, ConversionError>
+where
+    T: FromStr<Err = ParseIntError>,
+{
+    value
+        .trim()
+        .parse()
+        .map_err(|e: ParseIntError| match e.kind() {
+            IntErrorKind::PosOverflow | IntErrorKind::NegOverflow => {
+                ConversionError::IntegerOutOfRange(value.to_string(), type_name)
+            }
+            _ => ConversionError::InvalidInteger(value.to_string()),
+        })
+}
+
+pub fn parse_float<T: FromStr>(value: &str) -> Result<T, ConversionError> {
+    value
+        .trim()
+        .parse()
+        .map_err(|_| ConversionError::InvalidFloat(value.to_string()))
+}
+
+/// Parses a boolean from `true`/`false` or `1`/`0`
+pub fn parse_bool(value: &str) -> Result<bool, ConversionError> {
+    match value.trim() {
+        "true" | "1" => Ok(true),
+        "false" | "0" => Ok(false),
+        _ => Err(ConversionError::InvalidBool(value.to_string())),
+    }
+}
+
+/// Parses a decimal string like `-1234.000000000000000001` into the scaled
+/// integer `value * 10^scale`, encoded as a 256 bits two's complement.
+///
+/// The conversion is exact: fractional digits beyond `scale` are only accepted
+/// if they are zeros and the scaled value must fit in `precision` digits.
+pub fn parse_decimal(value: &str, precision: u32, scale: u32) -> Result<U256, ConversionError> {
+    let invalid = || ConversionError::InvalidDecimal(value.to_string());
+    let out_of_range = || ConversionError::DecimalOutOfRange(value.to_string(), precision, scale);
+
+    let (negative, digits) = split_sign(value.trim());
+    let (integer, fraction) = digits.split_once('.').unwrap_or((digits, ""));
+    if integer.is_empty() && fraction.is_empty() {
+        return Err(invalid());
+    }
+    if !integer
+        .bytes()
+        .chain(fraction.bytes())
+        .all(|b| b.is_ascii_digit())
+    {
+        return Err(invalid());
+    }
+
+    let scale_len = scale as usize;
+    let fraction = if fraction.len() > scale_len {
+        let (kept, dropped) = fraction.split_at(scale_len);
+        if dropped.bytes().any(|b| b != b'0') {
+            return Err(out_of_range());
+        }
+        kept
+    } else {
+        fraction
+    };
+
+    let scaled = format!("{integer}{fraction:0<scale_len$}");
+    let magnitude = U256::from_dec_str(&scaled).map_err(|_| out_of_range())?;
+    if magnitude >= U256::exp10(precision as usize) {
+        return Err(out_of_range());
+    }
+
+    Ok(if negative {
+        negate(magnitude)
+    } else {
+        magnitude
+    })
+}
+
+/// Parses an unsigned 256 bits integer from a decimal or a `0x` prefixed hexadecimal string
+pub fn parse_uint256(value: &str) -> Result<U256, ConversionError> {
+    let invalid = || ConversionError::InvalidInteger(value.to_string());
+    let out_of_range = || ConversionError::IntegerOutOfRange(value.to_string(), "UInt256");
+    let trimmed = value.trim();
+
+    let (digits, radix) = match strip_hex_prefix(trimmed) {
+        Some(hex) => (hex, 16),
+        None => (trimmed, 10),
+    };
+    if digits.is_empty() || !digits.chars().all(|c| c.is_digit(radix)) {
+        return Err(invalid());
+    }
+    U256::from_str_radix(digits, radix).map_err(|_| out_of_range())
+}
+
+/// Parses a signed 256 bits integer into its two's complement, from either:
+/// - a decimal string, optionally with a leading `-`
+/// - a `0x` prefixed hexadecimal string holding the two's complement bits,
+///   which is how EVM `int256` values are usually emitted
+pub fn parse_int256(value: &str) -> Result<U256, ConversionError> {
+    let invalid = || ConversionError::InvalidInteger(value.to_string());
+    let out_of_range = || ConversionError::IntegerOutOfRange(value.to_string(), "Int256");
+    let trimmed = value.trim();
+
+    if let Some(hex) = strip_hex_prefix(trimmed) {
+        if hex.is_empty() || !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return Err(invalid());
+        }
+        return U256::from_str_radix(hex, 16).map_err(|_| out_of_range());
+    }
+
+    let (negative, digits) = split_sign(trimmed);
+    if digits.is_empty() || !digits.bytes().all(|b| b.is_ascii_digit()) {
+        return Err(invalid());
+    }
+    let magnitude = U256::from_dec_str(digits).map_err(|_| out_of_range())?;
+    let min_magnitude = U256::one() << 255;
+    match negative {
+        true if magnitude <= min_magnitude => Ok(negate(magnitude)),
+        false if magnitude < min_magnitude => Ok(magnitude),
+        _ => Err(out_of_range()),
+    }
+}
+
+/// Splits an array field into its elements. The value is read as a JSON array
+/// and, if it is not one, as a list separated by `delimiter` when it is set.
+///
+/// JSON nulls are returned as `None`, strings without their quotes and any other
+/// JSON value (numbers, nested arrays, objects) as its JSON text.
+pub fn parse_array(
+    value: &str,
+    delimiter: Option<&str>,
+) -> Result<Vec<Option<String>>, ConversionError> {
+    let trimmed = value.trim();
+    if trimmed.is_empty() {
+        return Ok(vec![]);
+    }
+    if let Ok(elements) = serde_json::from_str::<Vec<serde_json::Value>>(trimmed) {
+        return Ok(elements.into_iter().map(json_to_field).collect());
+    }
+    match delimiter {
+        Some(delimiter) => Ok(trimmed
+            .split(delimiter)
+            .map(|element| Some(element.trim().to_string()))
+            .collect()),
+        None => Err(ConversionError::InvalidArray(value.to_string())),
+    }
+}
+
+/// Splits a map field, given as a JSON object, into its keys and values
+pub fn parse_map(value: &str) -> Result<Vec<(String, Option<String>)>, ConversionError> {
+    let trimmed = value.trim();
+    if trimmed.is_empty() {
+        return Ok(vec![]);
+    }
+    let entries = serde_json::from_str::<serde_json::Map<String, serde_json::Value>>(trimmed)
+        .map_err(|_| ConversionError::InvalidMap(value.to_string()))?;
+    Ok(entries
+        .into_iter()
+        .map(|(key, value)| (key, json_to_field(value)))
+        .collect())
+}
+
+/// Splits a tuple field into its elements, from either a JSON array with one value
+/// per element or, for named tuples, a JSON object keyed by the element names
+pub fn parse_tuple(
+    value: &str,
+    names: &[Option<&str>],
+) -> Result<Vec<Option<String>>, ConversionError> {
+    let invalid = || ConversionError::InvalidTuple(value.to_string());
+    match serde_json::from_str::<serde_json::Value>(value.trim()).map_err(|_| invalid())? {
+        serde_json::Value::Array(elements) if elements.len() == names.len() => {
+            Ok(elements.into_iter().map(json_to_field).collect())
+        }
+        serde_json::Value::Object(mut elements) if names.iter().all(Option::is_some) => Ok(names
+            .iter()
+            .flatten()
+            .map(|name| elements.remove(*name).and_then(json_to_field))
+            .collect()),
+        _ => Err(invalid()),
+    }
+}
+
+/// Converts a JSON value into the field string expected for its column type
+fn json_to_field(value: serde_json::Value) -> Option<String> {
+    match value {
+        serde_json::Value::Null => None,
+        serde_json::Value::String(value) => Some(value),
+        value => Some(value.to_string()),
+    }
+}
+
+/// Parses a UUID into its high and low 64 bits halves, which is how ClickHouse
+/// stores it. Accepts the usual hyphenated form, with or without hyphens and braces.
+pub fn parse_uuid(value: &str) -> Result<(u64, u64), ConversionError> {
+    let invalid = || ConversionError::InvalidUuid(value.to_string());
+    let hex = value
+        .trim()
+        .trim_start_matches('{')
+        .trim_end_matches('}')
+        .replace('-', "");
+    if hex.len() != 32 || !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return Err(invalid());
+    }
+    let high = u64::from_str_radix(&hex[..16], 16).map_err(|_| invalid())?;
+    let low = u64::from_str_radix(&hex[16..], 16).map_err(|_| invalid())?;
+    Ok((high, low))
+}
+
+pub fn parse_ipv4(value: &str) -> Result<Ipv4Addr, ConversionError> {
+    Ipv4Addr::from_str(value.trim())
+        .map_err(|_| ConversionError::InvalidIpAddress(value.to_string()))
+}
+
+/// Parses an IPv6 address, IPv4 addresses are mapped into IPv6
+pub fn parse_ipv6(value: &str) -> Result<Ipv6Addr, ConversionError> {
+    match IpAddr::from_str(value.trim()) {
+        Ok(IpAddr::V6(address)) => Ok(address),
+        Ok(IpAddr::V4(address)) => Ok(address.to_ipv6_mapped()),
+        Err(_) => Err(ConversionError::InvalidIpAddress(value.to_string())),
+    }
+}
+
+/// Parses an enum field given either by its name or by its numeric value
+pub fn parse_enum<T>(value: &str, variants: &[(String, T)]) -> Result<T, ConversionError>
+where
+    T: Copy + PartialEq + FromStr,
+{
+    let trimmed = value.trim();
+    let by_name = variants.iter().find(|(name, _)| name == trimmed);
+    let by_value = || {
+        let number = trimmed.parse::<T>().ok()?;
+        variants.iter().find(|(_, value)| *value == number)
+    };
+    by_name
+        .or_else(by_value)
+        .map(|(_, value)| *value)
+        .ok_or_else(|| ConversionError::InvalidEnum(value.to_string()))
+}
+
+/// Parses a point in time from one of the formats emitted by substreams modules:
+/// - RFC3339, which is also the string form of `google.protobuf.Timestamp`
+/// - the text form of `google.protobuf.Timestamp`, e.g. `seconds: 1690000000 nanos: 0`
+/// - unix seconds or unix milliseconds
+/// - `YYYY-MM-DD hh:mm:ss[.fff]` and `YYYY-MM-DD`, read in `timezone` (UTC when unset)
+pub fn parse_datetime(
+    value: &str,
+    timezone: Option<&str>,
+) -> Result<DateTime<Utc>, ConversionError> {
+    let invalid = || ConversionError::InvalidDateTime(value.to_string());
+    let trimmed = value.trim();
+
+    if let Ok(time) = DateTime::parse_from_rfc3339(trimmed) {
+        return Ok(time.with_timezone(&Utc));
+    }
+    if let Ok(number) = trimmed.parse::<i64>() {
+        let time = if number.abs() >= UNIX_MILLIS_THRESHOLD {
+            Utc.timestamp_millis_opt(number)
+        } else {
+            Utc.timestamp_opt(number, 0)
+        };
+        return time.single().ok_or_else(invalid);
+    }
+    if let Some(time) = parse_timestamp_text(trimmed) {
+        return Ok(time);
+    }
+    for format in ["%Y-%m-%d %H:%M:%S%.f", "%Y-%m-%dT%H:%M:%S%.f"] {
+        if let Ok(time) = NaiveDateTime::parse_from_str(trimmed, format) {
+            return from_local_datetime(value, &time, timezone);
+        }
+    }
+    if let Ok(date) = NaiveDate::parse_from_str(trimmed, "%Y-%m-%d") {
+        return from_local_datetime(value, &date.and_hms_opt(0, 0, 0).unwrap(), timezone);
+    }
+    Err(invalid())
+}
+
+/// Reads a naive date time as a wall clock time in `timezone`, as ClickHouse does.
+/// Times repeated by a DST change resolve to the earliest one.
+fn from_local_datetime(
+    value: &str,
+    time: &NaiveDateTime,
+    timezone: Option<&str>,
+) -> Result<DateTime<Utc>, ConversionError> {
+    let Some(name) = timezone else {
+        return Ok(Utc.from_utc_datetime(time));
+    };
+    let timezone = name
+        .parse::<Tz>()
+        .map_err(|_| ConversionError::InvalidTimezone(name.to_string()))?;
+    timezone
+        .from_local_datetime(time)
+        .earliest()
+        .map(|time| time.with_timezone(&Utc))
+        .ok_or_else(|| ConversionError::InvalidDateTime(value.to_string()))
+}
+
+/// Parses the text (or JSON object) form of a `google.protobuf.Timestamp`
+fn parse_timestamp_text(value: &str) -> Option<DateTime<Utc>> {
+    let normalized = value
+        .trim_start_matches('{')
+        .trim_end_matches('}')
+        .replace([':', ',', '"'], " ");
+    let mut parts = normalized.split_whitespace();
+    let mut seconds = None;
+    let mut nanos = 0;
+    while let Some(key) = parts.next() {
+        let number = parts.next()?.parse::<i64>().ok()?;
+        match key {
+            "seconds" => seconds = Some(number),
+            "nanos" => nanos = u32::try_from(number).ok()?,
+            _ => return None,
+        }
+    }
+    Utc.timestamp_opt(seconds?, nanos).single()
+}
+
+/// Days since the unix epoch, as stored by `Date` and `Date32`
+pub fn parse_days(value: &str) -> Result<i64, ConversionError> {
+    let epoch = NaiveDate::from_ymd_opt(1970, 1, 1).unwrap();
+    let date = parse_datetime(value, None)?.date_naive();
+    Ok(date.signed_duration_since(epoch).num_days())
+}
+
+/// Ticks of `10^-precision` seconds since the unix epoch, as stored by `DateTime64`
+pub fn parse_datetime64(
+    value: &str,
+    precision: u32,
+    timezone: Option<&str>,
+) -> Result<i64, ConversionError> {
+    let out_of_range = || ConversionError::DateTimeOutOfRange(value.to_string(), "DateTime64");
+    let time = parse_datetime(value, timezone)?;
+    let precision = precision.min(9);
+    let ticks = i64::from(time.timestamp_subsec_nanos()) / 10_i64.pow(9 - precision);
+    time.timestamp()
+        .checked_mul(10_i64.pow(precision))
+        .and_then(|seconds| seconds.checked_add(ticks))
+        .ok_or_else(out_of_range)
+}
+
+/// Decodes a hex string, with or without the `0x` prefix, into raw bytes.
+/// An odd number of digits is read as if it had a leading zero.
+pub fn decode_hex(value: &str) -> Result<Vec<u8>, ConversionError> {
+    let trimmed = value.trim();
+    let digits = strip_hex_prefix(trimmed).unwrap_or(trimmed);
+    let result = if digits.len() % 2 == 0 {
+        hex::decode(digits)
+    } else {
+        hex::decode(format!("0{digits}"))
+    };
+    result.map_err(|_| ConversionError::InvalidHex(value.to_string()))
+}
+
+/// Bytes of a FixedString, zero padded up to its size as ClickHouse does
+pub fn fixed_string_bytes(
+    value: &str,
+    bytes: Vec<u8>,
+    size: usize,
+) -> Result<Vec<u8>, ConversionError> {
+    if bytes.len() > size {
+        return Err(ConversionError::FixedStringOverflow(
+            value.to_string(),
+            size,
+        ));
+    }
+    let mut bytes = bytes;
+    bytes.resize(size, 0);
+    Ok(bytes)
+}
+
+fn strip_hex_prefix(value: &str) -> Option<&str> {
+    value
+        .strip_prefix("0x")
+        .or_else(|| value.strip_prefix("0X"))
+}
+
+fn split_sign(value: &str) -> (bool, &str) {
+    match value.strip_prefix('-') {
+        Some(value) => (true, value),
+        None => (false, value.strip_prefix('+').unwrap_or(value)),
+    }
+}
+
+/// Two's complement negation of a 256 bits integer
+fn negate(value: U256) -> U256 {
+    (!value).overflowing_add(U256::one()).0
+}
+
+#[cfg(test)]
+mod tests {
+    use primitive_types::U256;
+
+    use super::{
+        decode_hex, fixed_string_bytes, parse_array, parse_bool, parse_datetime, parse_datetime64,
+        parse_days, parse_decimal, parse_enum, parse_float, parse_int256, parse_integer,
+        parse_ipv4, parse_ipv6, parse_map, parse_tuple, parse_uint256, parse_uuid, ConversionError,
+    };
+
+    /// RowBinary encoding of an Int256, 32 bytes in little endian
+    fn row_binary(value: U256) -> Vec<u8> {
+        // this is how `value.0` is written by the RowBinary serializer
+        value.0.iter().flat_map(|limb| limb.to_le_bytes()).collect()
+    }
+
+    /// Decodes a RowBinary Int256 that fits in an i128
+    fn from_row_binary(bytes: &[u8]) -> i128 {
+        let (low, high) = bytes.split_at(16);
+        let sign = if low[15] & 0x80 == 0 { 0 } else { 0xff };
+        assert!(
+            high.iter().all(|b| *b == sign),
+            "value does not fit in i128"
+        );
+        i128::from_le_bytes(low.try_into().unwrap())
+    }
+
+    #[test]
+    fn test_parse_primitives() {
+        assert_eq!(parse_integer::<u64>(" 42", "UInt64"), Ok(42));
+        assert_eq!(parse_integer::<i8>("-128", "Int8"), Ok(-128));
+        assert_eq!(
+            parse_integer::<u64>("1.0", "UInt64"),
+            Err(ConversionError::InvalidInteger("1.0".into()))
+        );
+        assert_eq!(
+            parse_integer::<u8>("256", "UInt8"),
+            Err(ConversionError::IntegerOutOfRange("256".into(), "UInt8"))
+        );
+        assert_eq!(
+            parse_integer::<u32>("-1", "UInt32"),
+            Err(ConversionError::InvalidInteger("-1".into()))
+        );
+        assert_eq!(parse_float::<f64>("1.5"), Ok(1.5));
+        assert_eq!(
+            parse_float::<f32>("a"),
+            Err(ConversionError::InvalidFloat("a".into()))
+        );
+        assert_eq!(parse_bool("true"), Ok(true));
+        assert_eq!(parse_bool("0"), Ok(false));
+        assert_eq!(
+            parse_bool("yes"),
+            Err(ConversionError::InvalidBool("yes".into()))
+        );
+    }
+
+    #[test]
+    fn test_parse_decimal() {
+        assert_eq!(parse_decimal("1.5", 9, 2), Ok(U256::from(150)));
+        assert_eq!(parse_decimal("+10", 9, 2), Ok(U256::from(1000)));
+        assert_eq!(parse_decimal(".25", 9, 2), Ok(U256::from(25)));
+        assert_eq!(parse_decimal("1.230", 9, 2), Ok(U256::from(123)));
+        assert_eq!(
+            parse_decimal("1234.000000000000000001", 38, 18),
+            Ok(U256::from(1234000000000000000001u128))
+        );
+        assert_eq!(
+            parse_decimal("-1", 76, 0).map(|v| v.low_u128() as i128),
+            Ok(-1)
+        );
+        assert_eq!(parse_decimal("-1", 76, 0), Ok(U256::MAX));
+    }
+
+    #[test]
+    fn test_parse_decimal_errors() {
+        assert_eq!(
+            parse_decimal("1.234", 9, 2),
+            Err(ConversionError::DecimalOutOfRange("1.234".into(), 9, 2))
+        );
+        assert_eq!(
+            parse_decimal("10000000", 9, 2),
+            Err(ConversionError::DecimalOutOfRange("10000000".into(), 9, 2))
+        );
+        assert_eq!(
+            parse_decimal("1e5", 9, 2),
+            Err(ConversionError::InvalidDecimal("1e5".into()))
+        );
+        assert_eq!(
+            parse_decimal("-", 9, 2),
+            Err(ConversionError::InvalidDecimal("-".into()))
+        );
+    }
+
+    #[test]
+    fn test_parse_datetime() {
+        let expected = 1691157209;
+        for value in [
+            "2023-08-04T13:53:29+00:00",
+            "2023-08-04T15:53:29+02:00",
+            "2023-08-04T13:53:29Z",
+            "2023-08-04 13:53:29",
+            "1691157209",
+            "1691157209000",
+            "seconds: 1691157209 nanos: 0",
+            "{ seconds: 1691157209 }",
+            r#"{"seconds": "1691157209", "nanos": 0}"#,
+        ] {
+            assert_eq!(
+                parse_datetime(value, None).map(|t| t.timestamp()),
+                Ok(expected),
+                "{}",
+                value
+            );
+        }
+        assert_eq!(
+            parse_datetime("yesterday", None),
+            Err(ConversionError::InvalidDateTime("yesterday".into()))
+        );
+    }
+
+    #[test]
+    fn test_parse_datetime_with_timezone() {
+        let expected = 1691157209;
+        for value in [
+            "2023-08-04 09:53:29",
+            "2023-08-04T09:53:29",
+            "2023-08-04T13:53:29Z",
+            "1691157209",
+        ] {
+            assert_eq!(
+                parse_datetime(value, Some("America/New_York")).map(|t| t.timestamp()),
+                Ok(expected),
+                "{}",
+                value
+            );
+        }
+        assert_eq!(
+            parse_datetime("2023-08-04", Some("Asia/Tokyo")).map(|t| t.timestamp()),
+            Ok(1691074800)
+        );
+        assert_eq!(
+            parse_datetime("2023-08-04 13:53:29", Some("Mars/Olympus")),
+            Err(ConversionError::InvalidTimezone("Mars/Olympus".into()))
+        );
+    }
+
+    #[test]
+    fn test_parse_date_and_datetime64() {
+        assert_eq!(parse_days("1970-01-02"), Ok(1));
+        assert_eq!(parse_days("1969-12-31T23:00:00Z"), Ok(-1));
+        assert_eq!(parse_days("2023-08-04T13:53:29Z"), Ok(19573));
+        assert_eq!(
+            parse_datetime64("2023-08-04T13:53:29.123456Z", 3, None),
+            Ok(1691157209123)
+        );
+        assert_eq!(
+            parse_datetime64("1691157209123", 6, None),
+            Ok(1691157209123000)
+        );
+        assert_eq!(parse_datetime64("1691157209", 0, None), Ok(1691157209));
+        assert_eq!(
+            parse_datetime64("2023-08-04 10:53:29.123", 3, Some("America/Sao_Paulo")),
+            Ok(1691157209123)
+        );
+    }
+
+    #[test]
+    fn test_parse_int256() {
+        for value in [0, 1, -1, 42, -42, i64::MIN as i128, i128::MAX, i128::MIN] {
+            let bytes = row_binary(parse_int256(&value.to_string()).unwrap());
+            assert_eq!(bytes.len(), 32);
+            assert_eq!(from_row_binary(&bytes), value);
+        }
+
+        let mut minus_two = vec![0xff; 32];
+        minus_two[0] = 0xfe;
+        assert_eq!(row_binary(parse_int256("-2").unwrap()), minus_two);
+
+        let max = "57896044618658097711785492504343953926634992332820282019728792003956564819967";
+        let min = "-57896044618658097711785492504343953926634992332820282019728792003956564819968";
+        let mut max_bytes = vec![0xff; 32];
+        max_bytes[31] = 0x7f;
+        let mut min_bytes = vec![0; 32];
+        min_bytes[31] = 0x80;
+        assert_eq!(row_binary(parse_int256(max).unwrap()), max_bytes);
+        assert_eq!(row_binary(parse_int256(min).unwrap()), min_bytes);
+    }
+
+    #[test]
+    fn test_parse_int256_hex() {
+        assert_eq!(parse_int256("0x2a"), Ok(U256::from(42)));
+        assert_eq!(
+            parse_int256(&format!("0x{}", "f".repeat(64))),
+            parse_int256("-1")
+        );
+        assert_eq!(
+            parse_int256("0x"),
+            Err(ConversionError::InvalidInteger("0x".into()))
+        );
+        assert_eq!(
+            parse_int256(&format!("0x1{}", "0".repeat(64))),
+            Err(ConversionError::IntegerOutOfRange(
+                format!("0x1{}", "0".repeat(64)),
+                "Int256"
+            ))
+        );
+    }
+
+    #[test]
+    fn test_parse_int256_errors() {
+        let max = "57896044618658097711785492504343953926634992332820282019728792003956564819968";
+        let min = "-57896044618658097711785492504343953926634992332820282019728792003956564819969";
+        for value in [max, min] {
+            assert_eq!(
+                parse_int256(value),
+                Err(ConversionError::IntegerOutOfRange(value.into(), "Int256"))
+            );
+        }
+        for value in ["", "-", "1.0", "--1", "0xzz"] {
+            assert_eq!(
+                parse_int256(value),
+                Err(ConversionError::InvalidInteger(value.into()))
+            );
+        }
+    }
+
+    #[test]
+    fn test_parse_array() {
+        let some = |values: &[&str]| {
+            values
+                .iter()
+                .map(|v| Some(v.to_string()))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(parse_array(r#"["a", "b"]"#, None), Ok(some(&["a", "b"])));
+        assert_eq!(parse_array("[1, 2.5]", None), Ok(some(&["1", "2.5"])));
+        assert_eq!(
+            parse_array("[[1, 2], []]", None),
+            Ok(some(&["[1,2]", "[]"]))
+        );
+        assert_eq!(
+            parse_array(r#"["a", null]"#, None),
+            Ok(vec![Some("a".into()), None])
+        );
+        assert_eq!(parse_array("", None), Ok(vec![]));
+        assert_eq!(parse_array("[]", Some(",")), Ok(vec![]));
+        assert_eq!(parse_array("1, 2,3", Some(",")), Ok(some(&["1", "2", "3"])));
+        assert_eq!(parse_array("0xa|0xb", Some("|")), Ok(some(&["0xa", "0xb"])));
+        assert_eq!(
+            parse_array("1,2", None),
+            Err(ConversionError::InvalidArray("1,2".into()))
+        );
+    }
+
+    #[test]
+    fn test_parse_map() {
+        assert_eq!(
+            parse_map(r#"{"from": "0xa", "memo": null, "value": 10}"#),
+            Ok(vec![
+                ("from".into(), Some("0xa".into())),
+                ("memo".into(), None),
+                ("value".into(), Some("10".into())),
+            ])
+        );
+        assert_eq!(parse_map(""), Ok(vec![]));
+        assert_eq!(
+            parse_map("[1]"),
+            Err(ConversionError::InvalidMap("[1]".into()))
+        );
+    }
+
+    #[test]
+    fn test_parse_tuple() {
+        assert_eq!(
+            parse_tuple(r#"[1, "a"]"#, &[None, None]),
+            Ok(vec![Some("1".into()), Some("a".into())])
+        );
+        assert_eq!(
+            parse_tuple(r#"{"b": "x", "a": 1}"#, &[Some("a"), Some("b"), Some("c")]),
+            Ok(vec![Some("1".into()), Some("x".into()), None])
+        );
+        assert_eq!(
+            parse_tuple(r#"{"a": 1}"#, &[Some("a"), None]),
+            Err(ConversionError::InvalidTuple(r#"{"a": 1}"#.into()))
+        );
+        assert_eq!(
+            parse_tuple("[1]", &[None, None]),
+            Err(ConversionError::InvalidTuple("[1]".into()))
+        );
+    }
+
+    #[test]
+    fn test_parse_uuid() {
+        let expected = (0x61f0c4045cb311e7, 0x907ba6006ad3dba0);
+        for value in [
+            "61f0c404-5cb3-11e7-907b-a6006ad3dba0",
+            "61F0C4045CB311E7907BA6006AD3DBA0",
+            "{61f0c404-5cb3-11e7-907b-a6006ad3dba0}",
+        ] {
+            assert_eq!(parse_uuid(value), Ok(expected), "{}", value);
+        }
+        assert_eq!(
+            parse_uuid("61f0c404-5cb3"),
+            Err(ConversionError::InvalidUuid("61f0c404-5cb3".into()))
+        );
+    }
+
+    #[test]
+    fn test_parse_ip() {
+        assert_eq!(parse_ipv4("10.0.0.1").map(u32::from), Ok(0x0a000001));
+        assert_eq!(parse_ipv6("::1").map(|a| a.octets()[15]), Ok(1));
+        assert_eq!(
+            parse_ipv6("10.0.0.1").map(|a| a.to_string()),
+            Ok("::ffff:10.0.0.1".into())
+        );
+        assert_eq!(
+            parse_ipv4("::1"),
+            Err(ConversionError::InvalidIpAddress("::1".into()))
+        );
+    }
+
+    #[test]
+    fn test_parse_enum() {
+        let variants = vec![("buy".to_string(), 1_i8), ("sell".to_string(), -1)];
+        assert_eq!(parse_enum("buy", &variants), Ok(1));
+        assert_eq!(parse_enum("-1", &variants), Ok(-1));
+        assert_eq!(
+            parse_enum("2", &variants),
+            Err(ConversionError::InvalidEnum("2".into()))
+        );
+        assert_eq!(
+            parse_enum("hold", &variants),
+            Err(ConversionError::InvalidEnum("hold".into()))
+        );
+    }
+
+    #[test]
+    fn test_parse_uint256() {
+        assert_eq!(parse_uint256("42"), Ok(U256::from(42)));
+        assert_eq!(parse_uint256("0x2a"), Ok(U256::from(42)));
+        assert_eq!(
+            parse_uint256(&format!("0x{}", "f".repeat(64))),
+            Ok(U256::MAX)
+        );
+        assert_eq!(
+            parse_uint256("-1"),
+            Err(ConversionError::InvalidInteger("-1".into()))
+        );
+        assert_eq!(
+            parse_uint256(&format!("0x1{}", "0".repeat(64))),
+            Err(ConversionError::IntegerOutOfRange(
+                format!("0x1{}", "0".repeat(64)),
+                "UInt256"
+            ))
+        );
+    }
+
+    #[test]
+    fn test_decode_hex() {
+        let address = "0xa0b86991c6218b36c1d19d4a2e9eb0ce3606eb48";
+        let bytes = decode_hex(address).unwrap();
+        assert_eq!(bytes.len(), 20);
+        assert_eq!(bytes[0], 0xa0);
+        assert_eq!(decode_hex(&address[2..]), Ok(bytes.clone()));
+        assert_eq!(decode_hex("0xabc"), Ok(vec![0x0a, 0xbc]));
+        assert_eq!(decode_hex("0x"), Ok(vec![]));
+        assert_eq!(
+            decode_hex("0xzz"),
+            Err(ConversionError::InvalidHex("0xzz".into()))
+        );
+
+        assert_eq!(
+            fixed_string_bytes(address, bytes.clone(), 32).map(|b| b.len()),
+            Ok(32)
+        );
+        assert_eq!(
+            fixed_string_bytes(address, bytes, 16),
+            Err(ConversionError::FixedStringOverflow(address.into(), 16))
+        );
+    }
+}
@@ -1,37 +1,177 @@
 use std::{
-    collections::{HashMap, VecDeque},
-    time::Duration,
+    collections::{BTreeSet, HashMap, VecDeque},
+    fmt::Display,
+    num::NonZeroUsize,
+    str::FromStr,
+    time::{Duration, Instant},
 };
 
 use clickhouse::{
     inserter::{Inserter, RowInserter, SchemaInserter},
     Client, Row,
 };
+use futures03::future::join_all;
 use tracing::{debug, info, warn};
 use prost::Message;
 use serde::{Deserialize, Serialize};
 use substreams_database_change::pb::database::{
-    table_change::PrimaryKey, CompositePrimaryKey, DatabaseChanges, TableChange,
+    table_change::{Operation, PrimaryKey},
+    CompositePrimaryKey, DatabaseChanges, TableChange,
 };
 
 use crate::{
     convert_field_to_hash,
-    pb::sf::substreams::rpc::v2::BlockScopedData,
-    table_info::{DynamicInsert, DynamicTable},
+    pb::sf::substreams::rpc::v2::{BlockScopedData, BlockUndoSignal},
+    table_info::{
+        get_columns, get_table_information, quote_identifier, ColumnInfo, ColumnType,
+        DynamicInsert, DynamicTable, FieldConversionError, InserterSettings, MutationStrategy,
+        TableInfo, TableSettings,
+    },
     ElricError,
 };
 
-const BUFFER_LEN: usize = 12;
+/// Default number of non-final blocks kept in memory
+pub const BUFFER_LEN: usize = 12;
+
+const DEFAULT_DEAD_LETTER_TABLE: &str = "dead_letters";
+
+/// Inserter settings used for the values that are not configured
+pub const DEFAULT_INSERTER_SETTINGS: InserterSettings = InserterSettings {
+    send_timeout: Some(Duration::from_secs(5)),
+    end_timeout: Some(Duration::from_secs(20)),
+    period: Some(Duration::from_secs(15)),
+    max_entries: None,
+    max_bytes: None,
+};
+
+const DEFAULT_CURSORS_TABLE: &str = "cursors";
+
+/// Time after which an unknown table or column still missing after a refresh
+/// triggers a refresh again
+const UNRESOLVED_SCHEMA_RETRY: Duration = Duration::from_secs(60);
+
+/// Columns of the cursors table, matching `Cursor`
+const CURSOR_COLUMNS: [(&str, ColumnType); 4] = [
+    ("id", ColumnType::String),
+    ("cursor", ColumnType::String),
+    ("block_num", ColumnType::UInt64),
+    ("block_id", ColumnType::String),
+];
 
 pub struct DatabaseLoader {
     id: String,
+    client: Client,
     tables: HashMap<String, DynamicTable>,
     inserters: HashMap<String, Inserter<SchemaInserter<DynamicTable>, DynamicTable>>,
     cursor: Inserter<RowInserter<Cursor>, Cursor>,
+    cursor_table: CursorTable,
+    cursor_retention: CursorRetention,
+    persisted_cursors: u64,
     buffer: VecDeque<BlockScopedData>,
+    buffer_len: usize,
+    /// Number of the last block written in buffer mode
+    last_written_block: Option<u64>,
+    /// Block of the cursor the sink restarted from, the blocks up to it were
+    /// written by a previous run and are not in the reorg buffer
+    persisted_block: Option<u64>,
+    error_policy: ErrorPolicy,
+    dead_letter: Option<Inserter<RowInserter<DeadLetter>, DeadLetter>>,
+    undo_mode: UndoMode,
+    /// Whether each block is inserted with an `insert_deduplication_token`
+    deduplicate: bool,
+    /// Id of the block being inserted in deduplication mode
+    deduplicated_block: Option<String>,
+    /// Number of inserts started per table for the block being inserted
+    block_inserts: HashMap<String, usize>,
+    flush_policy: FlushPolicy,
+    /// Blocks written since the last flush
+    batch: Batch,
+    /// Settings of the inserters, overridden by the settings of each table
+    inserter_settings: InserterSettings,
+    /// Settings of the tables loaded when the schema is refreshed
+    table_settings: TableSettings,
+    /// Interval between two periodic refreshes of the schema
+    schema_refresh_interval: Option<Duration>,
+    last_schema_refresh: Instant,
+    /// Tables with an unknown table or column not found by a refresh, with the
+    /// time of that refresh, not refreshed again for it until
+    /// `UNRESOLVED_SCHEMA_RETRY` elapsed or another refresh reloads them
+    unresolved_tables: HashMap<String, Instant>,
+}
+
+/// How blocks that are not final yet are handled
+#[derive(Debug, Clone, PartialEq, Default)]
+pub enum UndoMode {
+    /// Blocks are kept in memory until they are final, undone blocks are dropped
+    #[default]
+    Buffer,
+    /// Blocks are written as soon as they are received and undone blocks are
+    /// compensated in ClickHouse: Collapsing tables get cancel rows, other tables
+    /// a lightweight delete of the rows above the last valid block number
+    Reorg { block_num_column: String },
+    /// Only final blocks are requested, they are written right away
+    FinalBlocksOnly,
+}
+
+/// What to do with a `TableChange` that can't be inserted, either because its
+/// table is unknown or because one of its fields doesn't fit its column
+#[derive(Debug, Clone, PartialEq, Default)]
+pub enum ErrorPolicy {
+    /// Stop the ingestion with an error
+    #[default]
+    Fail,
+    /// Log the change and continue
+    Skip,
+    /// Write the change to the given dead-letter table and continue
+    DeadLetter(String),
+}
+
+impl FromStr for ErrorPolicy {
+    type Err = String;
+
+    /// Parses `fail`, `skip`, `dead-letter` or `dead-letter:<table>`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            None if s == "fail" => Ok(ErrorPolicy::Fail),
+            None if s == "skip" => Ok(ErrorPolicy::Skip),
+            None if s == "dead-letter" => {
+                Ok(ErrorPolicy::DeadLetter(DEFAULT_DEAD_LETTER_TABLE.into()))
+            }
+            Some(("dead-letter", table)) if !table.is_empty() => {
+                Ok(ErrorPolicy::DeadLetter(table.into()))
+            }
+            _ => Err(format!(
+                "invalid error policy {}, expected fail, skip, dead-letter or dead-letter:<table>",
+                s
+            )),
+        }
+    }
+}
+
+/// What is written to ClickHouse for a `TableChange`
+#[derive(Debug, PartialEq)]
+pub enum Mutation {
+    /// Inserts a row with all its columns
+    Insert(HashMap<String, String>),
+    /// Inserts a tombstone row, the missing columns taking their default value
+    InsertTombstone(HashMap<String, String>),
+    /// Deletes the rows matching the primary key
+    Delete(HashMap<String, String>),
+}
+
+/// A `TableChange` that could not be inserted
+#[derive(Debug, Row, Serialize, Deserialize, PartialEq)]
+pub struct DeadLetter {
+    id: String,
+    block_num: u64,
+    block_id: String,
+    table: String,
+    /// The fields of the change as a JSON object
+    fields: String,
+    error: String,
 }
 
-#[derive(Debug, Row, Serialize, Deserialize)]
+#[derive(Debug, Row, Serialize, Deserialize, PartialEq)]
 pub struct Cursor {
     id: String,
     cursor: String,
@@ -40,9 +180,306 @@ pub struct Cursor {
 }
 
 impl Cursor {
+    pub fn new(id: String, cursor: String, block_num: u64, block_id: String) -> Self {
+        Self {
+            id,
+            cursor,
+            block_num,
+            block_id,
+        }
+    }
+
     pub fn cursor(&self) -> &String {
         &self.cursor
     }
+
+    pub fn block_num(&self) -> u64 {
+        self.block_num
+    }
+}
+
+impl Display for Cursor {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}\t{}\t{}\t{}",
+            self.id, self.block_num, self.block_id, self.cursor
+        )
+    }
+}
+
+/// When the rows written across blocks are flushed to ClickHouse and the cursor
+/// of the last block persisted. Without any limit, every block is flushed.
+#[derive(Debug, Clone, Default)]
+pub struct FlushPolicy {
+    /// Number of rows written
+    pub max_rows: Option<u64>,
+    /// Size of the `DatabaseChanges` payloads of the blocks
+    pub max_bytes: Option<u64>,
+    /// Number of blocks
+    pub max_blocks: Option<u64>,
+    /// Time since the first block of the batch, checked when a block is written
+    pub max_time: Option<Duration>,
+}
+
+impl FlushPolicy {
+    fn is_enabled(&self) -> bool {
+        self.max_rows.is_some()
+            || self.max_bytes.is_some()
+            || self.max_blocks.is_some()
+            || self.max_time.is_some()
+    }
+
+    fn is_reached(&self, batch: &Batch) -> bool {
+        let reached = |max: Option<u64>, value: u64| max.is_some_and(|max| value >= max);
+        !self.is_enabled()
+            || reached(self.max_rows, batch.rows)
+            || reached(self.max_bytes, batch.bytes)
+            || reached(self.max_blocks, batch.blocks)
+            || self
+                .max_time
+                .zip(batch.started)
+                .is_some_and(|(max, started)| started.elapsed() >= max)
+    }
+}
+
+/// Blocks written since the last flush
+#[derive(Debug, Default)]
+struct Batch {
+    rows: u64,
+    bytes: u64,
+    blocks: u64,
+    started: Option<Instant>,
+    /// Tables with rows written in the batch
+    tables: BTreeSet<String>,
+    /// Cursor, number and id of the last block of the batch
+    cursor: Option<(String, u64, String)>,
+}
+
+impl Batch {
+    fn add_block(&mut self, block: &BlockScopedData, bytes: usize) {
+        let clock = block.clock.as_ref().unwrap();
+        self.blocks += 1;
+        self.bytes += bytes as u64;
+        self.started.get_or_insert_with(Instant::now);
+        self.cursor = Some((block.cursor.clone(), clock.number, clock.id.clone()));
+    }
+}
+
+/// Which persisted cursors of an id are deleted, the latest one always being kept
+#[derive(Debug, Clone, Default)]
+pub struct CursorRetention {
+    /// Number of latest cursors kept
+    pub keep_last: Option<NonZeroUsize>,
+    /// Age after which cursors are deleted
+    pub ttl: Option<Duration>,
+    /// Merges the parts of the table with `OPTIMIZE TABLE ... FINAL` after the
+    /// deletes, removing the deleted cursors from disk
+    pub optimize: bool,
+    /// Number of persisted cursors between two cleanups, 0 disabling them
+    pub interval: u64,
+}
+
+impl CursorRetention {
+    fn is_enabled(&self) -> bool {
+        self.interval > 0 && (self.keep_last.is_some() || self.ttl.is_some() || self.optimize)
+    }
+}
+
+/// Table where the cursors are persisted, in the database of the client unless
+/// `database` is set
+#[derive(Debug, Clone, PartialEq)]
+pub struct CursorTable {
+    pub database: Option<String>,
+    pub name: String,
+}
+
+impl Default for CursorTable {
+    fn default() -> Self {
+        Self {
+            database: None,
+            name: DEFAULT_CURSORS_TABLE.into(),
+        }
+    }
+}
+
+impl CursorTable {
+    /// Latest cursor persisted for `id`
+    pub async fn latest(
+        &self,
+        client: &Client,
+        id: &str,
+    ) -> clickhouse::error::Result<Option<Cursor>> {
+        client
+            .query(&format!(
+                "SELECT ?fields FROM {} WHERE id = ? ORDER BY block_num DESC LIMIT 1",
+                self
+            ))
+            .bind(id)
+            .fetch_optional()
+            .await
+    }
+
+    /// Latest cursor persisted for `id` at or below `block_num`
+    pub async fn at_block(
+        &self,
+        client: &Client,
+        id: &str,
+        block_num: u64,
+    ) -> clickhouse::error::Result<Option<Cursor>> {
+        client
+            .query(&format!(
+                "SELECT ?fields FROM {} WHERE id = ? AND block_num <= ? \
+                 ORDER BY block_num DESC LIMIT 1",
+                self
+            ))
+            .bind(id)
+            .bind(block_num)
+            .fetch_optional()
+            .await
+    }
+
+    /// Cursors persisted for `id` that were not cleaned up, latest first
+    pub async fn history(
+        &self,
+        client: &Client,
+        id: &str,
+    ) -> clickhouse::error::Result<Vec<Cursor>> {
+        client
+            .query(&format!(
+                "SELECT ?fields FROM {} WHERE id = ? ORDER BY block_num DESC",
+                self
+            ))
+            .bind(id)
+            .fetch_all()
+            .await
+    }
+
+    /// Latest cursor of every id
+    pub async fn list(&self, client: &Client) -> clickhouse::error::Result<Vec<Cursor>> {
+        client
+            .query(&format!(
+                "SELECT ?fields FROM {} ORDER BY id, block_num DESC LIMIT 1 BY id",
+                self
+            ))
+            .fetch_all()
+            .await
+    }
+
+    /// Deletes the cursors of `id` above `block_num`, or all of them
+    pub async fn delete(
+        &self,
+        client: &Client,
+        id: &str,
+        block_num: Option<u64>,
+    ) -> clickhouse::error::Result<()> {
+        match block_num {
+            Some(block_num) => {
+                client
+                    .query(&format!(
+                        "DELETE FROM {} WHERE id = ? AND block_num > ?",
+                        self
+                    ))
+                    .bind(id)
+                    .bind(block_num)
+                    .execute()
+                    .await
+            }
+            None => {
+                client
+                    .query(&format!("DELETE FROM {} WHERE id = ?", self))
+                    .bind(id)
+                    .execute()
+                    .await
+            }
+        }
+    }
+
+    /// Replaces the cursors of `id` with a single cursor
+    pub async fn set(&self, client: &Client, cursor: Cursor) -> clickhouse::error::Result<()> {
+        self.delete(client, &cursor.id, None).await?;
+        let mut insert = client.insert(&self.to_string())?;
+        insert.write(&cursor).await?;
+        insert.end().await
+    }
+
+    /// Deletes the cursors of `id` outside of the retention policy
+    pub async fn cleanup(
+        &self,
+        client: &Client,
+        id: &str,
+        retention: &CursorRetention,
+    ) -> clickhouse::error::Result<()> {
+        let Some(latest) = self.latest(client, id).await? else {
+            return Ok(());
+        };
+        if let Some(keep_last) = retention.keep_last {
+            let oldest_kept = client
+                .query(&format!(
+                    "SELECT block_num FROM {} WHERE id = ? \
+                     ORDER BY block_num DESC LIMIT 1 OFFSET ?",
+                    self
+                ))
+                .bind(id)
+                .bind(keep_last.get() - 1)
+                .fetch_optional::<u64>()
+                .await?;
+            if let Some(oldest_kept) = oldest_kept {
+                client
+                    .query(&format!(
+                        "DELETE FROM {} WHERE id = ? AND block_num < ?",
+                        self
+                    ))
+                    .bind(id)
+                    .bind(oldest_kept)
+                    .execute()
+                    .await?;
+            }
+        }
+        if let Some(ttl) = retention.ttl {
+            client
+                .query(&format!(
+                    "DELETE FROM {} WHERE id = ? AND block_num < ? \
+                     AND inserted_at < now() - INTERVAL ? SECOND",
+                    self
+                ))
+                .bind(id)
+                .bind(latest.block_num)
+                .bind(ttl.as_secs())
+                .execute()
+                .await?;
+        }
+        if retention.optimize {
+            client
+                .query(&format!("OPTIMIZE TABLE {} FINAL", self))
+                .execute()
+                .await?;
+        }
+        debug!(id, "Cursors cleaned up");
+        Ok(())
+    }
+
+    /// Returns true if `database.name` is the cursors table of a client using `database`
+    pub fn is_table(&self, database: &str, name: &str) -> bool {
+        match &self.database {
+            Some(cursor_database) => cursor_database == database && self.name == name,
+            None => self.name == name,
+        }
+    }
+}
+
+impl Display for CursorTable {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.database {
+            Some(database) => write!(
+                f,
+                "{}.{}",
+                quote_identifier(database),
+                quote_identifier(&self.name)
+            ),
+            None => write!(f, "{}", quote_identifier(&self.name)),
+        }
+    }
 }
 
 impl DatabaseLoader {
@@ -50,13 +487,8 @@ impl DatabaseLoader {
         let mut inserters = HashMap::new();
 
         table.iter().for_each(|table| {
-            let table_name = table.table_name.clone();
-            let inserter = client
-                .inserter_with_schema(&table_name, table.clone())
-                .expect("inserter")
-                .with_timeouts(Some(Duration::from_secs(5)), Some(Duration::from_secs(20)))
-                .with_period(Some(Duration::from_secs(15)));
-            inserters.insert(table_name, inserter);
+            let inserter = create_table_inserter(&client, table, &DEFAULT_INSERTER_SETTINGS);
+            inserters.insert(table.table_name.clone(), inserter);
         });
 
         let tables = table
@@ -64,19 +496,120 @@ impl DatabaseLoader {
             .map(|t| (t.table_name.clone(), t))
             .collect();
 
-        let cursor = client
-            .inserter("cursors")
-            .expect("error while creating cursors inserter")
-            .with_timeouts(Some(Duration::from_secs(5)), Some(Duration::from_secs(20)))
-            .with_period(Some(Duration::from_secs(15)));
+        let cursor_table = CursorTable::default();
+        let cursor = create_cursor_inserter(&client, &cursor_table, &DEFAULT_INSERTER_SETTINGS);
 
         Self {
             id,
+            client,
             tables,
             inserters,
             cursor,
+            cursor_table,
+            cursor_retention: CursorRetention::default(),
+            persisted_cursors: 0,
             buffer: VecDeque::new(),
+            buffer_len: BUFFER_LEN,
+            last_written_block: None,
+            persisted_block: None,
+            error_policy: ErrorPolicy::default(),
+            dead_letter: None,
+            undo_mode: UndoMode::default(),
+            deduplicate: false,
+            deduplicated_block: None,
+            block_inserts: HashMap::new(),
+            flush_policy: FlushPolicy::default(),
+            batch: Batch::default(),
+            inserter_settings: DEFAULT_INSERTER_SETTINGS,
+            table_settings: TableSettings::default(),
+            schema_refresh_interval: None,
+            last_schema_refresh: Instant::now(),
+            unresolved_tables: HashMap::new(),
+        }
+    }
+
+    pub fn with_table_settings(mut self, table_settings: TableSettings) -> Self {
+        self.table_settings = table_settings;
+        self
+    }
+
+    /// Refreshes the schema of the tables every `interval`, checked when a block
+    /// is written
+    pub fn with_schema_refresh_interval(mut self, interval: Option<Duration>) -> Self {
+        self.schema_refresh_interval = interval;
+        self
+    }
+
+    /// Sets the settings of every inserter, overriding the default ones and
+    /// overridden by the settings of each table
+    pub fn with_inserter_settings(mut self, inserter_settings: InserterSettings) -> Self {
+        self.inserter_settings = DEFAULT_INSERTER_SETTINGS.merge(&inserter_settings);
+        for (name, table) in &self.tables {
+            let inserter = create_table_inserter(&self.client, table, &self.inserter_settings);
+            self.inserters.insert(name.clone(), inserter);
         }
+        self.cursor =
+            create_cursor_inserter(&self.client, &self.cursor_table, &self.inserter_settings);
+        if let ErrorPolicy::DeadLetter(table) = &self.error_policy {
+            self.dead_letter = Some(create_dead_letter_inserter(
+                &self.client,
+                table,
+                &self.inserter_settings,
+            ));
+        }
+        self
+    }
+
+    pub fn with_flush_policy(mut self, flush_policy: FlushPolicy) -> Self {
+        self.flush_policy = flush_policy;
+        self
+    }
+
+    /// Inserts the rows of each block and table in their own INSERT, tagged
+    /// with a deterministic `insert_deduplication_token`, so a block replayed
+    /// after a crash is dropped by ClickHouse instead of being duplicated
+    pub fn with_deduplication(mut self, deduplicate: bool) -> Self {
+        self.deduplicate = deduplicate;
+        self
+    }
+
+    pub fn with_cursor_table(mut self, cursor_table: CursorTable) -> Self {
+        self.cursor = create_cursor_inserter(&self.client, &cursor_table, &self.inserter_settings);
+        self.cursor_table = cursor_table;
+        self
+    }
+
+    pub fn with_cursor_retention(mut self, cursor_retention: CursorRetention) -> Self {
+        self.cursor_retention = cursor_retention;
+        self
+    }
+
+    pub fn with_persisted_block(mut self, persisted_block: Option<u64>) -> Self {
+        self.persisted_block = persisted_block;
+        self
+    }
+
+    pub fn with_buffer_len(mut self, buffer_len: usize) -> Self {
+        self.buffer_len = buffer_len;
+        self
+    }
+
+    pub fn with_undo_mode(mut self, undo_mode: UndoMode) -> Self {
+        self.undo_mode = undo_mode;
+        self
+    }
+
+    pub fn with_error_policy(mut self, error_policy: ErrorPolicy) -> Self {
+        self.dead_letter = match &error_policy {
+            ErrorPolicy::DeadLetter(table) => Some(create_dead_letter_inserter(
+                &self.client,
+                table,
+                &self.inserter_settings,
+            )),
+            _ => None,
+        };
+        self.error_policy = error_policy;
+        self
     }
 
     fn get_final_blocks_from_buffer(&mut self, data: BlockScopedData) -> Vec<BlockScopedData> {
@@ -89,12 +622,12 @@ impl DatabaseLoader {
             .position(|b| b.clock.as_ref().unwrap().number <= data.final_block_height)
             .map(|i| self.buffer.len() - i - 1);
 
-        let is_full_capacity = self.buffer.len() >= BUFFER_LEN;
+        let is_full_capacity = self.buffer.len() >= self.buffer_len;
 
         if is_full_capacity || final_block_index.is_some() {
             let len = match final_block_index {
                 Some(i) => i,
-                None => self.buffer.len() - BUFFER_LEN,
+                None => self.buffer.len() - self.buffer_len,
             };
 
             final_blocks.extend(self.buffer.drain(0..=len));
@@ -112,56 +645,183 @@ impl DatabaseLoader {
         &mut self,
         data: BlockScopedData,
     ) -> Result<(), ElricError> {
-        for block in self.get_final_blocks_from_buffer(data) {
+        let final_blocks = match self.undo_mode {
+            UndoMode::Buffer => self.get_final_blocks_from_buffer(data),
+            UndoMode::Reorg { .. } => return self.process_reorg_block(data).await,
+            UndoMode::FinalBlocksOnly => vec![data],
+        };
+        for block in final_blocks {
             let block_num = block.clock.as_ref().unwrap().number;
-            let block_id = block.clock.as_ref().unwrap().id.clone();
-            let cursor = block.cursor.clone();
             self.process_final_blocks(block).await?;
-            self.persist_cursor(cursor, block_num, block_id)
-                .await
-                .map_err(|_| ElricError::InsertCursorError)?;
+            self.last_written_block = Some(block_num);
+            if self.flush_policy.is_reached(&self.batch) {
+                self.flush_batch().await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Ends the inserts of the tables written since the last flush, then persists
+    /// the cursor of the last block, so the cursor never gets ahead of the rows
+    pub async fn flush_batch(&mut self) -> Result<(), ElricError> {
+        let batch = std::mem::take(&mut self.batch);
+        if self.flush_policy.is_enabled() {
+            for table in &batch.tables {
+                self.flush_table_inserter(table).await?;
+            }
+            debug!(
+                rows = batch.rows,
+                bytes = batch.bytes,
+                blocks = batch.blocks,
+                "Flushed batch"
+            );
+        }
+        let Some((cursor, block_num, block_id)) = batch.cursor else {
+            return Ok(());
+        };
+        self.persist_cursor(cursor, block_num, block_id)
+            .await
+            .map_err(|_| ElricError::InsertCursorError)
+    }
+
+    /// Writes a block right away, keeping it until it is final in case it gets undone
+    async fn process_reorg_block(&mut self, data: BlockScopedData) -> Result<(), ElricError> {
+        let final_block_height = data.final_block_height;
+        let block_num = data.clock.as_ref().unwrap().number;
+        self.process_final_blocks(data.clone()).await?;
+        if self.flush_policy.is_reached(&self.batch) {
+            self.flush_batch().await?;
+        }
+
+        self.buffer
+            .retain(|b| b.clock.as_ref().unwrap().number > final_block_height);
+        if block_num > final_block_height {
+            self.buffer.push_back(data);
         }
         Ok(())
     }
 
     async fn process_final_blocks(&mut self, data: BlockScopedData) -> Result<(), ElricError> {
+        let block_num = data.clock.as_ref().unwrap().number;
         let output = data.output.as_ref().unwrap().map_output.as_ref().unwrap();
         let database_changes = DatabaseChanges::decode(output.value.as_slice())?;
         let changes_length = database_changes.table_changes.len();
+        self.batch.add_block(&data, output.value.len());
+
+        let refresh_interval = self.schema_refresh_interval;
+        if refresh_interval.is_some_and(|interval| self.last_schema_refresh.elapsed() >= interval) {
+            self.refresh_schema().await?;
+        }
+
+        if self.deduplicate {
+            self.deduplicated_block = Some(data.clock.as_ref().unwrap().id.clone());
+            self.block_inserts.clear();
+        }
 
         let splitted_inserts = split_table_changes(database_changes.table_changes);
+        let mut deduplicated_tables = vec![];
 
         for (table, changes) in splitted_inserts {
-            let table_info = self
-                .get_table_info(&table)
-                .unwrap_or_else(|| panic!("It was not possible to find the table {}", table))
-                .clone();
-            let inserter = self.get_table_inserter(&table).unwrap();
+            if self.has_unknown_schema(&table, &changes) {
+                warn!(table, "Unknown table or column, refreshing the schema");
+                self.refresh_schema().await?;
+                if self.has_unknown_schema(&table, &changes) {
+                    self.unresolved_tables.insert(table.clone(), Instant::now());
+                }
+            }
+            let Some(table_info) = self.get_table_info(&table).cloned() else {
+                for change in changes {
+                    let (_, fields, _) = change_fields(change, None);
+                    let error = ElricError::TableNotFound(table.clone());
+                    self.process_invalid_change(&data, &table, &fields, error)
+                        .await?;
+                }
+                continue;
+            };
+            if self.deduplicate {
+                // starts an insert tagged with the token of this block
+                self.flush_table_inserter(&table).await?;
+            }
+            self.batch.tables.insert(table.clone());
             for change in changes {
-                let mut fields = convert_field_to_hash(change.fields);
+                let mutations = change_mutations(
+                    table_info.mutation_strategy(),
+                    table_info.key_column(),
+                    change,
+                    block_num,
+                );
+                for mutation in mutations {
+                    let (fields, dynamic_insert) = match mutation {
+                        Mutation::Insert(fields) => {
+                            let dynamic_insert = DynamicInsert::new(&table_info, &fields);
+                            (fields, dynamic_insert)
+                        }
+                        Mutation::InsertTombstone(fields) => {
+                            let dynamic_insert = DynamicInsert::with_defaults(&table_info, &fields);
+                            (fields, dynamic_insert)
+                        }
+                        Mutation::Delete(keys) if keys.is_empty() => {
+                            let error = ElricError::MissingPrimaryKey(table.clone());
+                            self.process_invalid_change(&data, &table, &keys, error)
+                                .await?;
+                            continue;
+                        }
+                        Mutation::Delete(keys) => {
+                            match table_info.key_condition(&keys) {
+                                Ok(condition) => self.delete_row(&table, &condition).await?,
+                                Err(e) => {
+                                    let error = conversion_error(&table, block_num, e);
+                                    self.process_invalid_change(&data, &table, &keys, error)
+                                        .await?;
+                                }
+                            }
+                            continue;
+                        }
+                    };
+                    let dynamic_insert = match dynamic_insert {
+                        Ok(dynamic_insert) => dynamic_insert,
+                        Err(e) => {
+                            let error = conversion_error(&table, block_num, e);
+                            self.process_invalid_change(&data, &table, &fields, error)
+                                .await?;
+                            continue;
+                        }
+                    };
 
-                match change.primary_key {
-                    Some(PrimaryKey::CompositePk(CompositePrimaryKey { keys })) => {
-                        fields.extend(keys);
-                    }
-                    Some(PrimaryKey::Pk(_)) => {}
-                    None => {}
-                };
-                let dynamic_insert = DynamicInsert::new(table_info.clone(), fields);
+                    self.get_table_inserter(&table)
+                        .unwrap()
+                        .write(&dynamic_insert)
+                        .await
+                        .map_err(|_| ElricError::InsertRowError)?;
+                    self.batch.rows += 1;
+                }
+            }
 
-                inserter
-                    .write(&dynamic_insert)
+            if self.deduplicate {
+                deduplicated_tables.push(table);
+            } else if !self.flush_policy.is_enabled() {
+                self.get_table_inserter(&table)
+                    .unwrap()
+                    .commit()
                     .await
-                    .map_err(|_| ElricError::InsertRowError)?;
+                    .map_err(|_| ElricError::CommitError)?;
             }
+        }
+
+        // the rows of the block are acknowledged before its cursor is written,
+        // the next inserts are not tagged until the next block
+        self.deduplicated_block = None;
+        for table in deduplicated_tables {
+            self.flush_table_inserter(&table).await?;
+        }
 
-            inserter
+        if let Some(dead_letter) = self.dead_letter.as_mut() {
+            dead_letter
                 .commit()
                 .await
-                .map_err(|_| ElricError::CommitError)?;
+                .map_err(|_| ElricError::InsertDeadLetterError)?;
         }
 
-        let block_num = data.clock.as_ref().unwrap().number;
         info!(
             block_num,
             changes_length,
@@ -174,22 +834,339 @@ impl DatabaseLoader {
         Ok(())
     }
 
-    pub fn process_block_undo_signal(&mut self, block_num_signal: u64) {
-        warn!(undo_block_num = block_num_signal, "Processing undo signal for block {}", block_num_signal);
-        let final_block_index = self
+    /// Whether the table or a column of the changes is unknown, unless a recent
+    /// refresh didn't find it either
+    fn has_unknown_schema(&self, table: &str, changes: &[TableChange]) -> bool {
+        let unresolved = self.unresolved_tables.get(table);
+        if unresolved.is_some_and(|refreshed| refreshed.elapsed() < UNRESOLVED_SCHEMA_RETRY) {
+            return false;
+        }
+        let Some(table_info) = self.tables.get(table) else {
+            return true;
+        };
+        changes
+            .iter()
+            .flat_map(|change| &change.fields)
+            .any(|field| !table_info.has_column(&field.name))
+    }
+
+    /// Loads the tables of the database, ending the inserts of the tables whose
+    /// columns changed and creating the inserters of the new ones
+    pub async fn refresh_schema(&mut self) -> Result<(), ElricError> {
+        self.last_schema_refresh = Instant::now();
+        let tables = load_dynamic_tables(&self.client, &self.table_settings, |table| {
+            !is_internal_table(table, &self.cursor_table, &self.error_policy)
+        })
+        .await?;
+
+        for table in tables {
+            let name = table.table_name.clone();
+            match self.tables.get(&name) {
+                Some(current) if current.columns() == table.columns() => continue,
+                Some(_) => info!(table = name, "Reloading table {}", name),
+                None => info!(table = name, "Loading table {}", name),
+            }
+            self.check_block_num_column(&table)?;
+            if let Some(inserter) = self.inserters.remove(&name) {
+                inserter.end().await.map_err(|_| ElricError::CommitError)?;
+            }
+            self.unresolved_tables.remove(&name);
+            let inserter = create_table_inserter(&self.client, &table, &self.inserter_settings);
+            self.inserters.insert(name.clone(), inserter);
+            self.tables.insert(name, table);
+        }
+        Ok(())
+    }
+
+    /// In reorg mode, checks that a table whose undone rows are deleted has the
+    /// block number column, before anything is written to it
+    fn check_block_num_column(&self, table: &DynamicTable) -> Result<(), ElricError> {
+        let UndoMode::Reorg { block_num_column } = &self.undo_mode else {
+            return Ok(());
+        };
+        match table.mutation_strategy() {
+            MutationStrategy::Collapsing { .. } => Ok(()),
+            _ if table.has_column(block_num_column) => Ok(()),
+            _ => Err(ElricError::MissingBlockNumColumn(
+                table.table_name.clone(),
+                block_num_column.clone(),
+            )),
+        }
+    }
+
+    /// Applies the error policy to a change that can't be inserted
+    async fn process_invalid_change(
+        &mut self,
+        block: &BlockScopedData,
+        table: &str,
+        fields: &HashMap<String, String>,
+        error: ElricError,
+    ) -> Result<(), ElricError> {
+        let clock = block.clock.as_ref().unwrap();
+        match self.error_policy {
+            ErrorPolicy::Fail => Err(error),
+            ErrorPolicy::Skip => {
+                warn!(block_num = clock.number, table, %error, "Skipping table change");
+                Ok(())
+            }
+            ErrorPolicy::DeadLetter(_) => {
+                warn!(
+                    block_num = clock.number,
+                    table,
+                    %error,
+                    "Sending table change to dead-letter table"
+                );
+                let dead_letter = DeadLetter {
+                    id: self.id.clone(),
+                    block_num: clock.number,
+                    block_id: clock.id.clone(),
+                    table: table.to_string(),
+                    fields: serde_json::to_string(fields).unwrap_or_default(),
+                    error: error.to_string(),
+                };
+                self.dead_letter
+                    .as_mut()
+                    .unwrap()
+                    .write(&dead_letter)
+                    .await
+                    .map_err(|_| ElricError::InsertDeadLetterError)
+            }
+        }
+    }
+
+    /// Runs a lightweight delete of the rows matching the condition on their primary key
+    async fn delete_row(&mut self, table: &str, condition: &str) -> Result<(), ElricError> {
+        // rows still buffered by the inserter would not be seen by the delete
+        self.flush_table_inserter(table).await?;
+
+        self.client
+            .query(&format!(
+                "DELETE FROM {} WHERE {}",
+                quote_identifier(table),
+                condition
+            ))
+            .execute()
+            .await
+            .map_err(ElricError::DeleteRowError)
+    }
+
+    /// Ends the current insert of a table and starts a new one
+    async fn flush_table_inserter(&mut self, table: &str) -> Result<(), ElricError> {
+        let Some(inserter) = self.inserters.remove(table) else {
+            return Ok(());
+        };
+        inserter.end().await.map_err(|_| ElricError::CommitError)?;
+        let client = self.table_client(table);
+        let inserter = create_table_inserter(&client, &self.tables[table], &self.inserter_settings);
+        self.inserters.insert(table.to_string(), inserter);
+        Ok(())
+    }
+
+    /// Client for the next insert of a table, carrying the deduplication token
+    /// of that insert when a block is being inserted in deduplication mode
+    fn table_client(&mut self, table: &str) -> Client {
+        let Some(block_id) = &self.deduplicated_block else {
+            return self.client.clone();
+        };
+        let insert = self.block_inserts.entry(table.to_string()).or_default();
+        let token = deduplication_token(&self.id, block_id, table, *insert);
+        *insert += 1;
+        self.client
+            .clone()
+            .with_option("insert_deduplication_token", token)
+    }
+
+    pub async fn process_undo_signal(&mut self, undo: BlockUndoSignal) -> Result<(), ElricError> {
+        let last_valid_block = undo.last_valid_block.unwrap_or_default();
+        let UndoMode::Reorg { block_num_column } = self.undo_mode.clone() else {
+            return self.process_block_undo_signal(last_valid_block.number);
+        };
+        warn!(
+            last_valid_block = last_valid_block.number,
+            "Undoing blocks written above block {}", last_valid_block.number
+        );
+        // the cursor of the pending batch must not be persisted after the rewind
+        self.flush_batch().await?;
+
+        let index = self
             .buffer
             .iter()
-            .rev()
-            .position(|b| block_num_signal == b.clock.as_ref().unwrap().number)
-            .map(|i| self.buffer.len() - i);
-
-        if let Some(index) = final_block_index {
-            let drained = self.buffer.drain(index..);
-            for d in drained {
-                let block_num = d.clock.as_ref().unwrap().number;
-                debug!(block_num, ?d, "New block drained");
+            .position(|b| b.clock.as_ref().unwrap().number > last_valid_block.number)
+            .unwrap_or(self.buffer.len());
+        let undone_blocks = self.buffer.drain(index..).collect::<Vec<_>>();
+
+        // nothing is written before every table is known to be undoable
+        let unbuffered = self
+            .persisted_block
+            .is_some_and(|persisted_block| persisted_block > last_valid_block.number);
+        let mut deleted_tables = BTreeSet::new();
+        for table_info in self.tables.values() {
+            let table = &table_info.table_name;
+            match table_info.mutation_strategy() {
+                MutationStrategy::Collapsing { .. } if unbuffered => {
+                    return Err(ElricError::UnbufferedUndo(
+                        table.clone(),
+                        last_valid_block.number,
+                    ));
+                }
+                MutationStrategy::Collapsing { .. } => {}
+                _ if !table_info.has_column(&block_num_column) => {
+                    return Err(ElricError::MissingBlockNumColumn(
+                        table.clone(),
+                        block_num_column,
+                    ));
+                }
+                // blocks written before a restart are not buffered, every table
+                // may have rows to delete
+                _ => {
+                    deleted_tables.insert(table.clone());
+                }
+            }
+        }
+
+        for block in undone_blocks.iter().rev() {
+            let block_num = block.clock.as_ref().unwrap().number;
+            let output = block.output.as_ref().unwrap().map_output.as_ref().unwrap();
+            let database_changes = DatabaseChanges::decode(output.value.as_slice())?;
+            for (table, changes) in split_table_changes(database_changes.table_changes) {
+                let Some(table_info) = self.get_table_info(&table).cloned() else {
+                    continue;
+                };
+                match table_info.mutation_strategy() {
+                    MutationStrategy::Collapsing { sign, .. } => {
+                        self.cancel_collapsing_rows(&table_info, sign, changes, block_num)
+                            .await?;
+                    }
+                    MutationStrategy::LightweightDelete => {
+                        warn!(table, block_num, "Undone deletes are not restored");
+                    }
+                    _ => {}
+                }
+            }
+        }
+
+        for table in deleted_tables {
+            // rows still buffered by the inserter would not be seen by the delete
+            self.flush_table_inserter(&table).await?;
+            self.client
+                .query(&format!(
+                    "DELETE FROM {} WHERE {} > ?",
+                    quote_identifier(&table),
+                    quote_identifier(&block_num_column)
+                ))
+                .bind(last_valid_block.number)
+                .execute()
+                .await
+                .map_err(ElricError::DeleteRowError)?;
+        }
+
+        self.rewind_cursor(
+            undo.last_valid_cursor,
+            last_valid_block.number,
+            last_valid_block.id,
+        )
+        .await
+    }
+
+    /// Writes the rows cancelling the rows a block wrote to a Collapsing table
+    async fn cancel_collapsing_rows(
+        &mut self,
+        table_info: &DynamicTable,
+        sign: &str,
+        changes: Vec<TableChange>,
+        block_num: u64,
+    ) -> Result<(), ElricError> {
+        let table = &table_info.table_name;
+        for change in changes.into_iter().rev() {
+            let mutations = change_mutations(
+                table_info.mutation_strategy(),
+                table_info.key_column(),
+                change,
+                block_num,
+            );
+            for mutation in mutations.into_iter().rev() {
+                let (mut fields, tombstone) = match mutation {
+                    Mutation::Insert(fields) => (fields, false),
+                    Mutation::InsertTombstone(fields) => (fields, true),
+                    Mutation::Delete(_) => continue,
+                };
+                let cancel_sign = match fields.get(sign).map(String::as_str) {
+                    Some("1") => "-1",
+                    _ => "1",
+                };
+                fields.insert(sign.to_string(), cancel_sign.to_string());
+                // converted like the written row, rows that could not be converted
+                // were never written
+                let dynamic_insert = if tombstone {
+                    DynamicInsert::with_defaults(table_info, &fields)
+                } else {
+                    DynamicInsert::new(table_info, &fields)
+                };
+                let Ok(dynamic_insert) = dynamic_insert else {
+                    continue;
+                };
+                self.get_table_inserter(table)
+                    .unwrap()
+                    .write(&dynamic_insert)
+                    .await
+                    .map_err(|_| ElricError::InsertRowError)?;
+            }
+        }
+        self.get_table_inserter(table)
+            .unwrap()
+            .commit()
+            .await
+            .map_err(|_| ElricError::CommitError)?;
+        Ok(())
+    }
+
+    /// Moves the persisted cursor back to the last valid block
+    async fn rewind_cursor(
+        &mut self,
+        cursor: String,
+        block_num: u64,
+        block_id: String,
+    ) -> Result<(), ElricError> {
+        let inserter =
+            create_cursor_inserter(&self.client, &self.cursor_table, &self.inserter_settings);
+        let inserter = std::mem::replace(&mut self.cursor, inserter);
+        inserter
+            .end()
+            .await
+            .map_err(|_| ElricError::InsertCursorError)?;
+        self.cursor_table
+            .delete(&self.client, &self.id, Some(block_num))
+            .await
+            .map_err(|_| ElricError::InsertCursorError)?;
+        self.persist_cursor(cursor, block_num, block_id)
+            .await
+            .map_err(|_| ElricError::InsertCursorError)
+    }
+
+    /// Drops the buffered blocks above `block_num_signal`, failing if blocks above it
+    /// were already written
+    pub fn process_block_undo_signal(&mut self, block_num_signal: u64) -> Result<(), ElricError> {
+        warn!(undo_block_num = block_num_signal, "Processing undo signal for block {}", block_num_signal);
+        if let Some(last_written_block) = self.last_written_block {
+            if last_written_block > block_num_signal {
+                return Err(ElricError::UndoTooDeep(
+                    block_num_signal,
+                    last_written_block,
+                ));
             }
         }
+
+        let index = self
+            .buffer
+            .iter()
+            .position(|b| b.clock.as_ref().unwrap().number > block_num_signal)
+            .unwrap_or(self.buffer.len());
+        let drained = self.buffer.drain(index..);
+        for d in drained {
+            let block_num = d.clock.as_ref().unwrap().number;
+            debug!(block_num, ?d, "New block drained");
+        }
+        Ok(())
     }
 
     pub async fn persist_cursor(
@@ -206,6 +1183,18 @@ impl DatabaseLoader {
         };
         self.cursor.write(&cursor).await?;
         self.cursor.commit().await?;
+
+        self.persisted_cursors += 1;
+        if self.cursor_retention.is_enabled()
+            && self.persisted_cursors % self.cursor_retention.interval == 0
+        {
+            let cleanup = self
+                .cursor_table
+                .cleanup(&self.client, &self.id, &self.cursor_retention);
+            if let Err(err) = cleanup.await {
+                warn!(%err, "Could not clean up cursors");
+            }
+        }
         Ok(())
     }
 
@@ -220,24 +1209,398 @@ impl DatabaseLoader {
         self.tables.get(table_name)
     }
 
-    pub async fn end(self) {
-        for (_, inserter) in self.inserters {
+    pub async fn end(mut self) {
+        for (_, inserter) in std::mem::take(&mut self.inserters) {
             inserter.end().await.expect("end");
         }
+        // the rows of the pending batch are written, its cursor can be persisted
+        if let Some((cursor, block_num, block_id)) = self.batch.cursor.take() {
+            self.persist_cursor(cursor, block_num, block_id)
+                .await
+                .expect("cursor persist");
+        }
         self.cursor.end().await.expect("cursor end");
+        if let Some(dead_letter) = self.dead_letter {
+            dead_letter.end().await.expect("dead-letter end");
+        }
     }
 }
 
-fn split_table_changes(table_changes: Vec<TableChange>) -> HashMap<String, Vec<TableChange>> {
-    let mut table_map: HashMap<String, Vec<TableChange>> =
-        HashMap::with_capacity(table_changes.len());
+/// Whether a table is the cursors or the dead-letter table, which are not
+/// loaded as data tables
+pub fn is_internal_table(
+    table: &TableInfo,
+    cursor_table: &CursorTable,
+    error_policy: &ErrorPolicy,
+) -> bool {
+    let is_dead_letter = match error_policy {
+        ErrorPolicy::DeadLetter(dead_letter) => &table.table_name == dead_letter,
+        _ => false,
+    };
+    is_dead_letter || cursor_table.is_table(&table.table_schema, &table.table_name)
+}
 
-    for change in table_changes {
-        // Check if the table name exists in the HashMap
-        if let Some(changes_for_table) = table_map.get_mut(&change.table) {
-            // If the table name exists, add the TableChange to its vector
-            changes_for_table.push(change);
-        } else {
+/// Loads the columns of the tables of the database matching `filter`
+pub async fn load_dynamic_tables(
+    client: &Client,
+    table_settings: &TableSettings,
+    filter: impl Fn(&TableInfo) -> bool,
+) -> Result<Vec<DynamicTable>, ElricError> {
+    let table_info = get_table_information(client).await?;
+    let tables = table_info
+        .iter()
+        .filter(|table| filter(table))
+        .map(|table| async {
+            let mut columns = get_columns(client, &table.table_schema, &table.table_name).await?;
+            columns.sort();
+            Ok(table_settings.dynamic_table(table, columns))
+        })
+        .collect::<Vec<_>>();
+    join_all(tables).await.into_iter().collect()
+}
+
+/// Deduplication token of an insert of a block into a table. Rows of a block
+/// are sent in a single insert per table, unless a lightweight delete splits
+/// them, in which case the following inserts are numbered. Each part is prefixed
+/// with its length so different parts never give the same token.
+fn deduplication_token(id: &str, block_id: &str, table: &str, insert: usize) -> String {
+    let token = [id, block_id, table]
+        .iter()
+        .map(|part| format!("{}:{}", part.len(), part))
+        .collect::<String>();
+    match insert {
+        0 => token,
+        n => format!("{}-{}", token, n),
+    }
+}
+
+/// Applies the timeouts, period and limits of `settings` to an inserter, whatever
+/// the type of the rows it writes
+macro_rules! with_inserter_settings {
+    ($inserter:expr, $settings:expr) => {{
+        let settings: &InserterSettings = $settings;
+        let mut inserter = $inserter
+            .with_timeouts(settings.send_timeout, settings.end_timeout)
+            .with_period(settings.period);
+        if let Some(max_entries) = settings.max_entries {
+            inserter = inserter.with_max_entries(max_entries);
+        }
+        if let Some(max_bytes) = settings.max_bytes {
+            inserter = inserter.with_max_bytes(max_bytes);
+        }
+        inserter
+    }};
+}
+
+fn create_cursor_inserter(
+    client: &Client,
+    cursor_table: &CursorTable,
+    settings: &InserterSettings,
+) -> Inserter<RowInserter<Cursor>, Cursor> {
+    let inserter = client
+        .inserter(&cursor_table.to_string())
+        .expect("error while creating cursors inserter");
+    with_inserter_settings!(inserter, settings)
+}
+
+fn create_dead_letter_inserter(
+    client: &Client,
+    table: &str,
+    settings: &InserterSettings,
+) -> Inserter<RowInserter<DeadLetter>, DeadLetter> {
+    let inserter = client
+        .inserter(table)
+        .expect("error while creating dead-letter inserter");
+    with_inserter_settings!(inserter, settings)
+}
+
+/// Creates the inserter of a table, its own settings overriding `settings`
+fn create_table_inserter(
+    client: &Client,
+    table: &DynamicTable,
+    settings: &InserterSettings,
+) -> Inserter<SchemaInserter<DynamicTable>, DynamicTable> {
+    let settings = settings.merge(table.inserter_settings());
+    let inserter = client
+        .inserter_with_schema(&table.table_name, table.clone())
+        .expect("inserter");
+    with_inserter_settings!(inserter, &settings)
+}
+
+/// Primary key, new values and old values of a change, both values including
+/// the primary key. A single-column primary key is set to `key_column`, unless
+/// the change has a value for that field. Empty old values are left out, as
+/// they are empty for fields that didn't exist before the change.
+fn change_fields(
+    change: TableChange,
+    key_column: Option<&str>,
+) -> (
+    HashMap<String, String>,
+    HashMap<String, String>,
+    HashMap<String, String>,
+) {
+    let keys = match change.primary_key {
+        Some(PrimaryKey::CompositePk(CompositePrimaryKey { keys })) => keys,
+        Some(PrimaryKey::Pk(pk)) => match key_column {
+            Some(key_column) => {
+                // a key column sent by the module keeps its value
+                let value = change
+                    .fields
+                    .iter()
+                    .find(|field| field.name == key_column)
+                    .map(|field| {
+                        if field.new_value.is_empty() {
+                            field.old_value.clone()
+                        } else {
+                            field.new_value.clone()
+                        }
+                    })
+                    .filter(|value| !value.is_empty())
+                    .unwrap_or(pk);
+                HashMap::from([(key_column.to_string(), value)])
+            }
+            None => HashMap::new(),
+        },
+        None => HashMap::new(),
+    };
+    let mut old_fields = keys.clone();
+    old_fields.extend(
+        change
+            .fields
+            .iter()
+            .filter(|field| !field.old_value.is_empty())
+            .map(|field| (field.name.clone(), field.old_value.clone())),
+    );
+    let mut fields = convert_field_to_hash(change.fields);
+    fields.extend(keys.clone());
+    (keys, fields, old_fields)
+}
+
+/// Error of a field of a table change that could not be converted to its column
+fn conversion_error(table: &str, block_num: u64, e: FieldConversionError) -> ElricError {
+    ElricError::ConvertFieldError {
+        table: table.to_string(),
+        column: e.column,
+        data_type: e.data_type.to_string(),
+        value: e.value,
+        block_num,
+        source: e.source,
+    }
+}
+
+/// Turns a change into what is written for it according to the mutation strategy
+/// of its table
+pub fn change_mutations(
+    strategy: &MutationStrategy,
+    key_column: Option<&str>,
+    change: TableChange,
+    block_num: u64,
+) -> Vec<Mutation> {
+    let operation = change.operation();
+    let (keys, mut fields, mut old_fields) = change_fields(change, key_column);
+    match (strategy, operation) {
+        (MutationStrategy::Insert, Operation::Delete) => {
+            warn!(
+                block_num,
+                ?keys,
+                "Ignoring delete, no mutation strategy is set"
+            );
+            vec![]
+        }
+        (MutationStrategy::Insert, _) => vec![Mutation::Insert(fields)],
+        (
+            MutationStrategy::Replacing {
+                version,
+                is_deleted,
+            },
+            operation,
+        ) => {
+            let deleted = operation == Operation::Delete;
+            if deleted {
+                fields = old_fields;
+            }
+            fields.insert(version.clone(), block_num.to_string());
+            match is_deleted {
+                Some(is_deleted) => {
+                    fields.insert(is_deleted.clone(), u8::from(deleted).to_string());
+                }
+                None if deleted => {
+                    debug!(
+                        block_num,
+                        ?keys,
+                        "Ignoring delete without is_deleted column"
+                    );
+                    return vec![];
+                }
+                None => {}
+            }
+            if deleted {
+                vec![Mutation::InsertTombstone(fields)]
+            } else {
+                vec![Mutation::Insert(fields)]
+            }
+        }
+        (MutationStrategy::Collapsing { sign, version }, operation) => {
+            if let Some(version) = version {
+                // the -1 row only collapses with a row of the same version, which is
+                // the new value when the module doesn't send the old one
+                match fields.get(version) {
+                    Some(value) if !old_fields.contains_key(version) => {
+                        old_fields.insert(version.clone(), value.clone());
+                    }
+                    _ => {}
+                }
+            }
+            old_fields.insert(sign.clone(), "-1".into());
+            fields.insert(sign.clone(), "1".into());
+            match operation {
+                Operation::Delete => vec![Mutation::InsertTombstone(old_fields)],
+                Operation::Update => vec![
+                    Mutation::InsertTombstone(old_fields),
+                    Mutation::Insert(fields),
+                ],
+                Operation::Create | Operation::Unset => vec![Mutation::Insert(fields)],
+            }
+        }
+        (MutationStrategy::LightweightDelete, Operation::Delete) => vec![Mutation::Delete(keys)],
+        (MutationStrategy::LightweightDelete, _) => vec![Mutation::Insert(fields)],
+    }
+}
+
+/// Creates the dead-letter table if it doesn't exist
+pub async fn create_dead_letter_table(client: &Client, table: &str) -> Result<(), ElricError> {
+    let query = format!(
+        "
+        CREATE TABLE IF NOT EXISTS {} (
+            id String,
+            block_num UInt64,
+            block_id String,
+            table String,
+            fields String,
+            error String,
+            inserted_at DateTime DEFAULT now()
+        )
+        ENGINE = MergeTree
+        ORDER BY (id, table, block_num)
+        ",
+        quote_identifier(table)
+    );
+    client
+        .query(&query)
+        .execute()
+        .await
+        .map_err(ElricError::LoadSchemaError)
+}
+
+/// Creates the cursors table, and its database, if it doesn't exist, otherwise
+/// checks its columns
+pub async fn setup_cursors_table(
+    client: &Client,
+    cursor_table: &CursorTable,
+    retention: &CursorRetention,
+) -> Result<(), ElricError> {
+    if let Some(database) = &cursor_table.database {
+        client
+            .query(&format!(
+                "CREATE DATABASE IF NOT EXISTS {}",
+                quote_identifier(database)
+            ))
+            .execute()
+            .await
+            .map_err(ElricError::LoadSchemaError)?;
+    }
+    let query = format!(
+        "
+        CREATE TABLE IF NOT EXISTS {} (
+            id String,
+            cursor String,
+            block_num UInt64,
+            block_id String,
+            inserted_at DateTime DEFAULT now()
+        )
+        ENGINE = MergeTree
+        ORDER BY (id, block_num)
+        ",
+        cursor_table
+    );
+    client
+        .query(&query)
+        .execute()
+        .await
+        .map_err(ElricError::LoadSchemaError)?;
+
+    check_cursors_table(client, cursor_table, retention).await
+}
+
+/// Checks that the cursors table exists and has the columns of a cursor, and
+/// the insertion time when cursors are cleaned up by age
+pub async fn check_cursors_table(
+    client: &Client,
+    cursor_table: &CursorTable,
+    retention: &CursorRetention,
+) -> Result<(), ElricError> {
+    let database = match &cursor_table.database {
+        Some(database) => database.as_str(),
+        None => client.database().unwrap_or("default"),
+    };
+    let columns = get_columns(client, database, &cursor_table.name).await?;
+    if columns.is_empty() {
+        return Err(ElricError::TableNotFound(cursor_table.to_string()));
+    }
+    check_cursor_columns(&columns, retention)
+}
+
+/// Checks that the cursors table has the columns of `Cursor`
+fn check_cursor_columns(
+    columns: &[ColumnInfo],
+    retention: &CursorRetention,
+) -> Result<(), ElricError> {
+    for (column_name, data_type) in CURSOR_COLUMNS {
+        let column = columns
+            .iter()
+            .find(|column| column.column_name == column_name);
+        match column {
+            Some(column) if column.data_type == data_type => {}
+            Some(column) => {
+                return Err(ElricError::InvalidCursorsTable(format!(
+                    "column {} is {} instead of {}",
+                    column_name, column.data_type, data_type
+                )))
+            }
+            None => {
+                return Err(ElricError::InvalidCursorsTable(format!(
+                    "missing column {} {}",
+                    column_name, data_type
+                )))
+            }
+        }
+    }
+    // the cleanup by age filters on the insertion time of the cursors
+    let has_inserted_at = columns.iter().any(|column| {
+        column.column_name == "inserted_at"
+            && matches!(
+                column.data_type,
+                ColumnType::DateTime(_) | ColumnType::DateTime64(..)
+            )
+    });
+    if retention.ttl.is_some() && !has_inserted_at {
+        return Err(ElricError::InvalidCursorsTable(
+            "missing column inserted_at DateTime, required by --cursor-ttl".into(),
+        ));
+    }
+    Ok(())
+}
+
+pub fn split_table_changes(table_changes: Vec<TableChange>) -> HashMap<String, Vec<TableChange>> {
+    let mut table_map: HashMap<String, Vec<TableChange>> =
+        HashMap::with_capacity(table_changes.len());
+
+    for change in table_changes {
+        // Check if the table name exists in the HashMap
+        if let Some(changes_for_table) = table_map.get_mut(&change.table) {
+            // If the table name exists, add the TableChange to its vector
+            changes_for_table.push(change);
+        } else {
             // If the table name does not exist, create a new vector and add the TableChange to it
             table_map.insert(change.table.clone(), vec![change]);
         }
@@ -248,25 +1611,44 @@ fn split_table_changes(table_changes: Vec<TableChange>) -> HashMap<String, Vec<T
 
 #[cfg(test)]
 mod tests {
-    use std::collections::{HashMap, VecDeque};
+    use std::{
+        collections::{HashMap, VecDeque},
+        num::NonZeroUsize,
+        sync::{Arc, Mutex},
+        time::{Duration, Instant},
+    };
 
     use clickhouse::{test, Client, Row};
     use prost::Message;
     use prost_types::Any;
-    use serde::Deserialize;
-    use substreams_database_change::pb::database::{DatabaseChanges, Field, TableChange};
+    use serde::{Deserialize, Serialize};
+    use substreams_database_change::pb::database::{
+        table_change::{Operation, PrimaryKey},
+        CompositePrimaryKey, DatabaseChanges, Field, TableChange,
+    };
+    use tokio::{
+        io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+        net::TcpListener,
+    };
     use tracing_test::traced_test;
+    use url::Url;
 
     use crate::{
+        convert::ConversionError,
         loader::BUFFER_LEN,
         pb::sf::substreams::{
-            rpc::v2::{BlockScopedData, MapModuleOutput},
-            v1::Clock,
+            rpc::v2::{BlockScopedData, BlockUndoSignal, MapModuleOutput},
+            v1::{BlockRef, Clock},
         },
-        table_info::{ColumnInfo, ColumnType, DynamicTable},
+        table_info::{ColumnInfo, ColumnType, ConversionOptions, DynamicTable, MutationStrategy},
+        ElricError,
     };
 
-    use super::DatabaseLoader;
+    use super::{
+        change_mutations, check_cursor_columns, check_cursors_table, deduplication_token,
+        setup_cursors_table, Batch, Cursor, CursorRetention, CursorTable, DatabaseLoader,
+        DeadLetter, ErrorPolicy, FlushPolicy, Mutation, UndoMode, UNRESOLVED_SCHEMA_RETRY,
+    };
     use anyhow::Result;
 
     #[tokio::test]
@@ -283,16 +1665,10 @@ mod tests {
         }
         let mock = test::Mock::new();
         let client = Client::default().with_url(mock.url());
-        let cursor = client.inserter("test").unwrap();
-        let mut loader = DatabaseLoader {
-            id: "test".into(),
-            buffer,
-            tables: HashMap::new(),
-            inserters: HashMap::new(),
-            cursor,
-        };
+        let mut loader = DatabaseLoader::new("test".into(), client, vec![]);
+        loader.buffer = buffer;
         let v = 8;
-        loader.process_block_undo_signal(v);
+        loader.process_block_undo_signal(v).unwrap();
         let result = loader
             .buffer
             .iter()
@@ -302,17 +1678,184 @@ mod tests {
     }
 
     #[tokio::test]
-    async fn test_buffer() {
+    async fn test_undo_block_signal_too_deep() {
         let mock = test::Mock::new();
         let client = Client::default().with_url(mock.url());
-        let cursor = client.inserter("test").unwrap();
-        let mut loader = DatabaseLoader {
-            id: "test".into(),
-            buffer: VecDeque::new(),
-            tables: HashMap::new(),
-            inserters: HashMap::new(),
-            cursor,
+        let mut loader = DatabaseLoader::new("test".into(), client, vec![]).with_buffer_len(4);
+        loader.last_written_block = Some(10);
+        loader.process_block_undo_signal(12).unwrap();
+        let error = loader.process_block_undo_signal(8).unwrap_err();
+        assert!(matches!(error, ElricError::UndoTooDeep(8, 10)));
+    }
+
+    #[tokio::test]
+    async fn test_set_cursor() -> Result<()> {
+        let mock = test::Mock::new();
+        let client = Client::default().with_url(mock.url());
+        let delete_recording = mock.add(test::handlers::record_ddl());
+        let inserts_recording = mock.add(test::handlers::record());
+        let cursor = Cursor::new("test".into(), "cursor".into(), 10, "0xabc".into());
+        CursorTable::default().set(&client, cursor).await?;
+        assert!(delete_recording
+            .query()
+            .await
+            .contains("DELETE FROM `cursors` WHERE id = 'test'"));
+        let inserts: Vec<Cursor> = inserts_recording.collect().await;
+        assert_eq!(
+            inserts,
+            vec![Cursor::new(
+                "test".into(),
+                "cursor".into(),
+                10,
+                "0xabc".into()
+            )]
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_latest_cursor() -> Result<()> {
+        let mock = test::Mock::new();
+        let client = Client::default().with_url(mock.url());
+        let cursor = Cursor::new("test".into(), "cursor".into(), 10, "0xabc".into());
+        mock.add(test::handlers::provide(vec![cursor]));
+        let latest = CursorTable::default().latest(&client, "test").await?;
+        assert_eq!(
+            latest,
+            Some(Cursor::new(
+                "test".into(),
+                "cursor".into(),
+                10,
+                "0xabc".into()
+            ))
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_cleanup_cursors() -> Result<()> {
+        let mock = test::Mock::new();
+        let client = Client::default().with_url(mock.url());
+        let cursor = Cursor::new("test".into(), "cursor".into(), 10, "0xabc".into());
+        mock.add(test::handlers::provide(vec![cursor]));
+        mock.add(test::handlers::provide(vec![8u64]));
+        let delete_recording = mock.add(test::handlers::record_ddl());
+        let retention = CursorRetention {
+            keep_last: NonZeroUsize::new(3),
+            interval: 1,
+            ..Default::default()
+        };
+        CursorTable::default()
+            .cleanup(&client, "test", &retention)
+            .await?;
+        assert!(delete_recording
+            .query()
+            .await
+            .contains("DELETE FROM `cursors` WHERE id = 'test' AND block_num < 8"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_cursor_table() {
+        let cursor_table = CursorTable::default();
+        assert_eq!(cursor_table.to_string(), "`cursors`");
+        assert!(cursor_table.is_table("default", "cursors"));
+        let cursor_table = CursorTable {
+            database: Some("elric_state".into()),
+            name: "uniswap_cursors".into(),
+        };
+        assert_eq!(cursor_table.to_string(), "`elric_state`.`uniswap_cursors`");
+        assert!(cursor_table.is_table("elric_state", "uniswap_cursors"));
+        assert!(!cursor_table.is_table("default", "uniswap_cursors"));
+    }
+
+    #[test]
+    fn test_check_cursor_columns() {
+        let column = |column_name: &str, data_type| ColumnInfo {
+            column_name: column_name.into(),
+            data_type,
+        };
+        let mut columns = vec![
+            column("block_id", ColumnType::String),
+            column("block_num", ColumnType::UInt64),
+            column("cursor", ColumnType::String),
+            column("id", ColumnType::String),
+        ];
+        let retention = CursorRetention::default();
+        assert!(check_cursor_columns(&columns, &retention).is_ok());
+
+        let ttl = CursorRetention {
+            ttl: Some(Duration::from_secs(3600)),
+            ..Default::default()
         };
+        assert!(matches!(
+            check_cursor_columns(&columns, &ttl),
+            Err(ElricError::InvalidCursorsTable(_))
+        ));
+        columns.push(column("inserted_at", ColumnType::DateTime(None)));
+        assert!(check_cursor_columns(&columns, &ttl).is_ok());
+
+        columns[1] = column("block_num", ColumnType::UInt32);
+        assert!(matches!(
+            check_cursor_columns(&columns, &retention),
+            Err(ElricError::InvalidCursorsTable(_))
+        ));
+        columns.remove(1);
+        assert!(matches!(
+            check_cursor_columns(&columns, &retention),
+            Err(ElricError::InvalidCursorsTable(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_check_missing_cursors_table() {
+        let mock = test::Mock::new();
+        let client = Client::default().with_url(mock.url());
+        mock.add(test::handlers::provide(Vec::<TestColumnInfo>::new()));
+        let cursor_table = CursorTable::default();
+        assert!(matches!(
+            check_cursors_table(&client, &cursor_table, &CursorRetention::default()).await,
+            Err(ElricError::TableNotFound(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_setup_cursors_table() -> Result<()> {
+        let mock = test::Mock::new();
+        let client = Client::default().with_url(mock.url());
+        let create_recording = mock.add(test::handlers::record_ddl());
+        mock.add(test::handlers::provide(
+            [
+                ("id", "String"),
+                ("cursor", "String"),
+                ("block_num", "UInt64"),
+                ("block_id", "String"),
+                ("inserted_at", "DateTime"),
+            ]
+            .map(|(column_name, data_type)| TestColumnInfo {
+                column_name: column_name.into(),
+                data_type: data_type.into(),
+            })
+            .to_vec(),
+        ));
+        setup_cursors_table(
+            &client,
+            &CursorTable::default(),
+            &CursorRetention::default(),
+        )
+        .await?;
+        // every cursor of an id is kept, merges don't collapse them
+        let query = create_recording.query().await;
+        assert!(query.contains("ENGINE = MergeTree"));
+        assert!(query.contains("ORDER BY (id, block_num)"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_buffer() {
+        let mock = test::Mock::new();
+        let client = Client::default().with_url(mock.url());
+        let mut loader = DatabaseLoader::new("test".into(), client, vec![]);
         for i in 0..10 {
             let data = BlockScopedData {
                 clock: Some(Clock {
@@ -401,6 +1944,1143 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_deduplication_token() {
+        assert_eq!(
+            deduplication_token("id", "0xabc", "test", 0),
+            "2:id5:0xabc4:test"
+        );
+        assert_eq!(
+            deduplication_token("id", "0xabc", "test", 2),
+            "2:id5:0xabc4:test-2"
+        );
+        assert_ne!(
+            deduplication_token("id", "0xabc", "test", 0),
+            deduplication_token("id", "0xabd", "test", 0)
+        );
+        assert_ne!(
+            deduplication_token("a-b", "c", "d", 0),
+            deduplication_token("a", "b-c", "d", 0)
+        );
+        assert_ne!(
+            deduplication_token("id", "0xabc", "test-1", 0),
+            deduplication_token("id", "0xabc", "test", 1)
+        );
+    }
+
+    #[test]
+    fn test_flush_policy() {
+        let batch = Batch {
+            rows: 10,
+            bytes: 100,
+            blocks: 2,
+            ..Default::default()
+        };
+        assert!(FlushPolicy::default().is_reached(&batch));
+        let policy = FlushPolicy {
+            max_rows: Some(20),
+            max_blocks: Some(3),
+            ..Default::default()
+        };
+        assert!(!policy.is_reached(&batch));
+        let policy = FlushPolicy {
+            max_bytes: Some(100),
+            ..Default::default()
+        };
+        assert!(policy.is_reached(&batch));
+        let policy = FlushPolicy {
+            max_time: Some(Duration::ZERO),
+            ..Default::default()
+        };
+        assert!(!policy.is_reached(&batch));
+        let batch = Batch {
+            started: Some(Instant::now()),
+            ..batch
+        };
+        assert!(policy.is_reached(&batch));
+    }
+
+    #[tokio::test]
+    async fn test_process_batched_data() -> Result<()> {
+        let mock = test::Mock::new();
+        let client = Client::default().with_url(mock.url());
+        let table = vec![DynamicTable::new(
+            "test",
+            vec![ColumnInfo {
+                column_name: "test".into(),
+                data_type: ColumnType::UInt64,
+            }],
+        )];
+        let mut loader = DatabaseLoader::new("test".into(), client, table)
+            .with_undo_mode(UndoMode::FinalBlocksOnly)
+            .with_flush_policy(FlushPolicy {
+                max_blocks: Some(2),
+                ..Default::default()
+            });
+        let block = |number: u64| {
+            let mut data = create_block_scoped_data(vec![TableChange {
+                table: "test".into(),
+                fields: vec![Field {
+                    name: "test".into(),
+                    new_value: number.to_string(),
+                    ..Default::default()
+                }],
+                ..Default::default()
+            }]);
+            data.clock.as_mut().unwrap().number = number;
+            data.clock.as_mut().unwrap().id = format!("0x{}", number);
+            data.cursor = format!("cursor{}", number);
+            data
+        };
+
+        let inserts_recording = mock.add(test::handlers::record());
+        let cursors_recording = mock.add(test::handlers::record());
+        loader.process_block_scoped_data(block(1)).await?;
+        assert_eq!(loader.batch.blocks, 1);
+        loader.process_block_scoped_data(block(2)).await?;
+        assert_eq!(loader.batch.blocks, 0);
+
+        // both blocks are sent in one insert, before the cursor of the last block
+        let inserts: Vec<TestInsert> = inserts_recording.collect().await;
+        assert_eq!(
+            inserts,
+            vec![TestInsert { test: 1 }, TestInsert { test: 2 }]
+        );
+        loader.end().await;
+        let cursors: Vec<Cursor> = cursors_recording.collect().await;
+        assert_eq!(
+            cursors,
+            vec![Cursor::new(
+                "test".into(),
+                "cursor2".into(),
+                2,
+                "0x2".into()
+            )]
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_process_deduplicated_data() -> Result<()> {
+        let mock = test::Mock::new();
+        let client = Client::default().with_url(mock.url());
+        let table = vec![DynamicTable::new(
+            "test",
+            vec![ColumnInfo {
+                column_name: "test".into(),
+                data_type: ColumnType::UInt64,
+            }],
+        )];
+        let mut loader =
+            DatabaseLoader::new("test".into(), client, table.clone()).with_deduplication(true);
+        let block = |value: &str, block_id: &str| {
+            let mut data = create_block_scoped_data(vec![TableChange {
+                table: "test".into(),
+                fields: vec![Field {
+                    name: "test".into(),
+                    new_value: value.into(),
+                    ..Default::default()
+                }],
+                ..Default::default()
+            }]);
+            data.clock.as_mut().unwrap().id = block_id.into();
+            data
+        };
+
+        // each block is sent in its own insert, acknowledged before the next
+        // block, so a replayed block carries the same token as the first attempt
+        let first_recording = mock.add(test::handlers::record());
+        loader.process_final_blocks(block("1", "0xa")).await?;
+        let inserts: Vec<TestInsert> = first_recording.collect().await;
+        assert_eq!(inserts, vec![TestInsert { test: 1 }]);
+
+        let second_recording = mock.add(test::handlers::record());
+        loader.process_final_blocks(block("2", "0xb")).await?;
+        let inserts: Vec<TestInsert> = second_recording.collect().await;
+        assert_eq!(inserts, vec![TestInsert { test: 2 }]);
+
+        assert!(loader.deduplicated_block.is_none());
+        assert_eq!(loader.block_inserts.get("test"), Some(&1));
+        loader.end().await;
+
+        // the token is sent with the insert, and again when the block is replayed
+        // by a restarted sink
+        let (url, settings) = serve_recording_settings().await;
+        for replay in [false, true] {
+            let client = Client::default().with_url(&url);
+            let mut loader =
+                DatabaseLoader::new("test".into(), client, table.clone()).with_deduplication(true);
+            loader.process_final_blocks(block("1", "0xa")).await?;
+            if !replay {
+                loader.process_final_blocks(block("2", "0xb")).await?;
+            }
+            loader.end().await;
+        }
+        let tokens = settings
+            .lock()
+            .unwrap()
+            .iter()
+            .filter_map(|settings| settings.get("insert_deduplication_token").cloned())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            tokens,
+            vec![
+                deduplication_token("test", "0xa", "test", 0),
+                deduplication_token("test", "0xb", "test", 0),
+                deduplication_token("test", "0xa", "test", 0),
+            ]
+        );
+        Ok(())
+    }
+
+    /// Serves a client, answering every request with an empty response and
+    /// recording the settings sent in its url
+    async fn serve_recording_settings() -> (String, Arc<Mutex<Vec<HashMap<String, String>>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let settings = Arc::new(Mutex::new(Vec::new()));
+        let recorded = settings.clone();
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let settings = settings.clone();
+                tokio::spawn(async move {
+                    let mut stream = BufReader::new(stream);
+                    let mut line = String::new();
+                    // connections are kept alive, each iteration reads a request
+                    while stream.read_line(&mut line).await.unwrap_or(0) > 0 {
+                        let target = line.split_whitespace().nth(1).unwrap_or_default();
+                        let url = Url::parse(&format!("http://localhost{}", target)).unwrap();
+                        settings
+                            .lock()
+                            .unwrap()
+                            .push(url.query_pairs().into_owned().collect());
+
+                        let mut chunked = false;
+                        let mut length = 0;
+                        loop {
+                            line.clear();
+                            stream.read_line(&mut line).await.unwrap();
+                            let header = line.trim_end().to_lowercase();
+                            if header.is_empty() {
+                                break;
+                            } else if header == "transfer-encoding: chunked" {
+                                chunked = true;
+                            } else if let Some(value) = header.strip_prefix("content-length: ") {
+                                length = value.parse().unwrap();
+                            }
+                        }
+                        if chunked {
+                            loop {
+                                line.clear();
+                                stream.read_line(&mut line).await.unwrap();
+                                let size = line.trim_end().split(';').next().unwrap();
+                                let size = usize::from_str_radix(size, 16).unwrap();
+                                // the chunk is followed by a line break
+                                let mut chunk = vec![0; size + 2];
+                                stream.read_exact(&mut chunk).await.unwrap();
+                                if size == 0 {
+                                    break;
+                                }
+                            }
+                        } else {
+                            let mut body = vec![0; length];
+                            stream.read_exact(&mut body).await.unwrap();
+                        }
+                        stream
+                            .get_mut()
+                            .write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 0\r\n\r\n")
+                            .await
+                            .unwrap();
+                        line.clear();
+                    }
+                });
+            }
+        });
+        (url, recorded)
+    }
+
+    #[derive(Row, Serialize)]
+    struct TestTableInfo {
+        table_schema: String,
+        table_name: String,
+        primary_key: String,
+    }
+
+    #[derive(Row, Serialize)]
+    struct TestColumnInfo {
+        column_name: String,
+        data_type: String,
+    }
+
+    #[tokio::test]
+    async fn test_refresh_schema_on_unknown_table() -> Result<()> {
+        let mock = test::Mock::new();
+        let client = Client::default().with_url(mock.url());
+        let mut loader = DatabaseLoader::new("test".into(), client, vec![]);
+        let data = create_block_scoped_data(vec![TableChange {
+            table: "test".into(),
+            fields: vec![Field {
+                name: "test".into(),
+                new_value: "1".into(),
+                ..Default::default()
+            }],
+            ..Default::default()
+        }]);
+        // the table was missing from a refresh long enough ago to be retried
+        let refreshed = Instant::now().checked_sub(UNRESOLVED_SCHEMA_RETRY).unwrap();
+        loader.unresolved_tables.insert("test".into(), refreshed);
+
+        mock.add(test::handlers::provide(vec![
+            TestTableInfo {
+                table_schema: "default".into(),
+                table_name: "cursors".into(),
+                primary_key: "id".into(),
+            },
+            TestTableInfo {
+                table_schema: "default".into(),
+                table_name: "test".into(),
+                primary_key: "".into(),
+            },
+        ]));
+        mock.add(test::handlers::provide(vec![TestColumnInfo {
+            column_name: "test".into(),
+            data_type: "UInt64".into(),
+        }]));
+        let inserts_recording = mock.add(test::handlers::record());
+        loader.process_final_blocks(data).await?;
+        assert!(!loader.tables.contains_key("cursors"));
+        assert!(loader.unresolved_tables.is_empty());
+        loader.end().await;
+
+        let inserts: Vec<TestInsert> = inserts_recording.collect().await;
+        assert_eq!(inserts, vec![TestInsert { test: 1 }]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_has_unknown_schema() {
+        let client = Client::default();
+        let table = vec![DynamicTable::new(
+            "test",
+            vec![ColumnInfo {
+                column_name: "test".into(),
+                data_type: ColumnType::UInt64,
+            }],
+        )];
+        let mut loader = DatabaseLoader::new("test".into(), client, table);
+        let change = |name: &str| TableChange {
+            table: "test".into(),
+            fields: vec![Field {
+                name: name.into(),
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        assert!(!loader.has_unknown_schema("test", &[change("test")]));
+        assert!(loader.has_unknown_schema("test", &[change("other")]));
+        assert!(loader.has_unknown_schema("other", &[change("test")]));
+        loader
+            .unresolved_tables
+            .insert("other".into(), Instant::now());
+        assert!(!loader.has_unknown_schema("other", &[change("test")]));
+        let retried = Instant::now().checked_sub(UNRESOLVED_SCHEMA_RETRY).unwrap();
+        loader.unresolved_tables.insert("other".into(), retried);
+        assert!(loader.has_unknown_schema("other", &[change("test")]));
+    }
+
+    #[derive(Row, Debug, Deserialize, PartialEq)]
+    #[allow(dead_code)]
+    struct TestNullableInsert {
+        test: u64,
+        to: Option<u64>,
+    }
+
+    #[tokio::test]
+    async fn test_process_nullable_data() -> Result<()> {
+        let mut mock = test::Mock::new();
+        mock.non_exhaustive();
+        let client = Client::default().with_url(mock.url());
+        let table = vec![DynamicTable::new(
+            "test",
+            vec![
+                ColumnInfo {
+                    column_name: "test".into(),
+                    data_type: ColumnType::UInt64,
+                },
+                ColumnInfo {
+                    column_name: "to".into(),
+                    data_type: ColumnType::Nullable(Box::new(ColumnType::UInt64)),
+                },
+            ],
+        )];
+        let mut loader = DatabaseLoader::new("test".into(), client, table);
+        let field = |name: &str, value: &str| Field {
+            name: name.into(),
+            new_value: value.into(),
+            ..Default::default()
+        };
+        let changes = vec![
+            TableChange {
+                table: "test".into(),
+                fields: vec![field("test", "1"), field("to", "5")],
+                ..Default::default()
+            },
+            TableChange {
+                table: "test".into(),
+                fields: vec![field("test", "2"), field("to", "")],
+                ..Default::default()
+            },
+            TableChange {
+                table: "test".into(),
+                fields: vec![field("test", "3")],
+                ..Default::default()
+            },
+        ];
+        let data = create_block_scoped_data(changes);
+        let inserts_recording = mock.add(test::handlers::record());
+        loader.process_final_blocks(data).await?;
+        loader.end().await;
+        let inserts: Vec<TestNullableInsert> = inserts_recording.collect().await;
+        assert_eq!(
+            inserts,
+            vec![
+                TestNullableInsert {
+                    test: 1,
+                    to: Some(5)
+                },
+                TestNullableInsert { test: 2, to: None },
+                TestNullableInsert { test: 3, to: None },
+            ]
+        );
+        Ok(())
+    }
+
+    #[derive(Row, Debug, Deserialize, PartialEq)]
+    #[allow(dead_code)]
+    struct TestArrayInsert {
+        ids: Vec<u64>,
+        topics: Vec<String>,
+    }
+
+    #[tokio::test]
+    async fn test_process_array_data() -> Result<()> {
+        let mut mock = test::Mock::new();
+        mock.non_exhaustive();
+        let client = Client::default().with_url(mock.url());
+        let options = ConversionOptions {
+            array_delimiter: Some(",".into()),
+            ..Default::default()
+        };
+        let table = vec![DynamicTable::new(
+            "test",
+            vec![
+                ColumnInfo {
+                    column_name: "ids".into(),
+                    data_type: ColumnType::Array(Box::new(ColumnType::UInt64)),
+                },
+                ColumnInfo {
+                    column_name: "topics".into(),
+                    data_type: ColumnType::Array(Box::new(ColumnType::String)),
+                },
+            ],
+        )
+        .with_options(options)];
+        let mut loader = DatabaseLoader::new("test".into(), client, table);
+        let field = |name: &str, value: &str| Field {
+            name: name.into(),
+            new_value: value.into(),
+            ..Default::default()
+        };
+        let changes = vec![
+            TableChange {
+                table: "test".into(),
+                fields: vec![field("ids", "[1, 2]"), field("topics", r#"["a", "b"]"#)],
+                ..Default::default()
+            },
+            TableChange {
+                table: "test".into(),
+                fields: vec![field("ids", "3,4"), field("topics", "")],
+                ..Default::default()
+            },
+        ];
+        let data = create_block_scoped_data(changes);
+        let inserts_recording = mock.add(test::handlers::record());
+        loader.process_final_blocks(data).await?;
+        loader.end().await;
+        let inserts: Vec<TestArrayInsert> = inserts_recording.collect().await;
+        assert_eq!(
+            inserts,
+            vec![
+                TestArrayInsert {
+                    ids: vec![1, 2],
+                    topics: vec!["a".into(), "b".into()]
+                },
+                TestArrayInsert {
+                    ids: vec![3, 4],
+                    topics: vec![]
+                },
+            ]
+        );
+        Ok(())
+    }
+
+    #[derive(Row, Debug, Deserialize, PartialEq)]
+    #[allow(dead_code)]
+    struct TestMapTupleInsert {
+        attributes: Vec<(String, String)>,
+        pair: (u8, String),
+    }
+
+    #[tokio::test]
+    async fn test_process_map_tuple_data() -> Result<()> {
+        let mut mock = test::Mock::new();
+        mock.non_exhaustive();
+        let client = Client::default().with_url(mock.url());
+        let table = vec![DynamicTable::new(
+            "test",
+            vec![
+                ColumnInfo {
+                    column_name: "attributes".into(),
+                    data_type: ColumnType::Map(
+                        Box::new(ColumnType::String),
+                        Box::new(ColumnType::String),
+                    ),
+                },
+                ColumnInfo {
+                    column_name: "pair".into(),
+                    data_type: ColumnType::Tuple(vec![
+                        (Some("index".into()), ColumnType::UInt8),
+                        (Some("name".into()), ColumnType::String),
+                    ]),
+                },
+            ],
+        )];
+        let mut loader = DatabaseLoader::new("test".into(), client, table);
+        let field = |name: &str, value: &str| Field {
+            name: name.into(),
+            new_value: value.into(),
+            ..Default::default()
+        };
+        let changes = vec![
+            TableChange {
+                table: "test".into(),
+                fields: vec![
+                    field("attributes", r#"{"a": "1", "b": "2"}"#),
+                    field("pair", r#"[1, "x"]"#),
+                ],
+                ..Default::default()
+            },
+            TableChange {
+                table: "test".into(),
+                fields: vec![
+                    field("attributes", "{}"),
+                    field("pair", r#"{"name": "y", "index": 2}"#),
+                ],
+                ..Default::default()
+            },
+        ];
+        let data = create_block_scoped_data(changes);
+        let inserts_recording = mock.add(test::handlers::record());
+        loader.process_final_blocks(data).await?;
+        loader.end().await;
+        let inserts: Vec<TestMapTupleInsert> = inserts_recording.collect().await;
+        assert_eq!(
+            inserts,
+            vec![
+                TestMapTupleInsert {
+                    attributes: vec![("a".into(), "1".into()), ("b".into(), "2".into())],
+                    pair: (1, "x".into()),
+                },
+                TestMapTupleInsert {
+                    attributes: vec![],
+                    pair: (2, "y".into()),
+                },
+            ]
+        );
+        Ok(())
+    }
+
+    #[derive(Row, Debug, Deserialize, PartialEq)]
+    #[allow(dead_code)]
+    struct TestHexInsert {
+        address: [u8; 20],
+        name: [u8; 4],
+    }
+
+    #[tokio::test]
+    async fn test_process_hex_data() -> Result<()> {
+        let mut mock = test::Mock::new();
+        mock.non_exhaustive();
+        let client = Client::default().with_url(mock.url());
+        let hex_decode = vec!["test.address".to_string()];
+        let table = vec![DynamicTable::new(
+            "test",
+            vec![
+                ColumnInfo {
+                    column_name: "address".into(),
+                    data_type: ColumnType::FixedString(20),
+                },
+                ColumnInfo {
+                    column_name: "name".into(),
+                    data_type: ColumnType::FixedString(4),
+                },
+            ],
+        )
+        .with_options(ConversionOptions::default().with_hex_decode("test", &hex_decode))];
+        let mut loader = DatabaseLoader::new("test".into(), client, table);
+        let field = |name: &str, value: &str| Field {
+            name: name.into(),
+            new_value: value.into(),
+            ..Default::default()
+        };
+        let changes = vec![TableChange {
+            table: "test".into(),
+            fields: vec![
+                field("address", "0x00000000000000000000000000000000000000ff"),
+                field("name", "ab"),
+            ],
+            ..Default::default()
+        }];
+        let data = create_block_scoped_data(changes);
+        let inserts_recording = mock.add(test::handlers::record());
+        loader.process_final_blocks(data).await?;
+        loader.end().await;
+        let inserts: Vec<TestHexInsert> = inserts_recording.collect().await;
+        let mut address = [0; 20];
+        address[19] = 0xff;
+        assert_eq!(
+            inserts,
+            vec![TestHexInsert {
+                address,
+                name: [b'a', b'b', 0, 0],
+            }]
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_process_invalid_data() -> Result<()> {
+        let mut mock = test::Mock::new();
+        mock.non_exhaustive();
+        let client = Client::default().with_url(mock.url());
+        let table = vec![DynamicTable::new(
+            "test",
+            vec![ColumnInfo {
+                column_name: "test".into(),
+                data_type: ColumnType::UInt64,
+            }],
+        )];
+        let mut loader = DatabaseLoader::new("test".into(), client, table);
+        let changes = vec![TableChange {
+            table: "test".into(),
+            fields: vec![Field {
+                name: "test".into(),
+                new_value: "1.0".into(),
+                ..Default::default()
+            }],
+            ..Default::default()
+        }];
+        let data = create_block_scoped_data(changes);
+        let error = loader.process_final_blocks(data).await.unwrap_err();
+        // the message is also written to the dead letter table
+        assert_eq!(
+            error.to_string(),
+            "Could not convert value 1.0 of column test.test (UInt64) at block 0: \
+             invalid integer value \"1.0\""
+        );
+        match error {
+            ElricError::ConvertFieldError {
+                table,
+                column,
+                data_type,
+                value,
+                block_num,
+                source,
+            } => {
+                assert_eq!(table, "test");
+                assert_eq!(column, "test");
+                assert_eq!(data_type, "UInt64");
+                assert_eq!(value, Some("1.0".into()));
+                assert_eq!(block_num, 0);
+                assert_eq!(source, ConversionError::InvalidInteger("1.0".into()));
+            }
+            error => panic!("unexpected error {}", error),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_error_policy() {
+        assert_eq!("fail".parse(), Ok(ErrorPolicy::Fail));
+        assert_eq!("skip".parse(), Ok(ErrorPolicy::Skip));
+        assert_eq!(
+            "dead-letter".parse(),
+            Ok(ErrorPolicy::DeadLetter("dead_letters".into()))
+        );
+        assert_eq!(
+            "dead-letter:errors".parse(),
+            Ok(ErrorPolicy::DeadLetter("errors".into()))
+        );
+        assert!("dead-letter:".parse::<ErrorPolicy>().is_err());
+        assert!("ignore".parse::<ErrorPolicy>().is_err());
+    }
+
+    fn create_keyed_change(operation: Operation, fields: Vec<Field>) -> TableChange {
+        let mut change = TableChange {
+            table: "test".into(),
+            primary_key: Some(PrimaryKey::CompositePk(CompositePrimaryKey {
+                keys: HashMap::from([("id".to_string(), "1".to_string())]),
+            })),
+            fields,
+            ..Default::default()
+        };
+        change.set_operation(operation);
+        change
+    }
+
+    #[test]
+    fn test_replacing_mutations() {
+        let strategy = MutationStrategy::Replacing {
+            version: "version".into(),
+            is_deleted: Some("is_deleted".into()),
+        };
+        let update = create_keyed_change(
+            Operation::Update,
+            vec![Field {
+                name: "value".into(),
+                old_value: "a".into(),
+                new_value: "b".into(),
+            }],
+        );
+        assert_eq!(
+            change_mutations(&strategy, None, update, 10),
+            vec![Mutation::Insert(HashMap::from([
+                ("id".to_string(), "1".to_string()),
+                ("value".to_string(), "b".to_string()),
+                ("version".to_string(), "10".to_string()),
+                ("is_deleted".to_string(), "0".to_string()),
+            ]))]
+        );
+        let delete = create_keyed_change(Operation::Delete, vec![]);
+        assert_eq!(
+            change_mutations(&strategy, None, delete, 11),
+            vec![Mutation::InsertTombstone(HashMap::from([
+                ("id".to_string(), "1".to_string()),
+                ("version".to_string(), "11".to_string()),
+                ("is_deleted".to_string(), "1".to_string()),
+            ]))]
+        );
+    }
+
+    #[test]
+    fn test_delete_mutations() {
+        let delete = create_keyed_change(Operation::Delete, vec![]);
+        assert_eq!(
+            change_mutations(&MutationStrategy::Insert, None, delete.clone(), 10),
+            vec![]
+        );
+        assert_eq!(
+            change_mutations(&MutationStrategy::LightweightDelete, None, delete, 10),
+            vec![Mutation::Delete(HashMap::from([(
+                "id".to_string(),
+                "1".to_string()
+            )]))]
+        );
+    }
+
+    #[test]
+    fn test_versioned_collapsing_mutations() {
+        let strategy = MutationStrategy::Collapsing {
+            sign: "sign".into(),
+            version: Some("version".into()),
+        };
+        let delete = create_keyed_change(
+            Operation::Delete,
+            vec![Field {
+                name: "version".into(),
+                new_value: "3".into(),
+                ..Default::default()
+            }],
+        );
+        assert_eq!(
+            change_mutations(&strategy, None, delete, 10),
+            vec![Mutation::InsertTombstone(HashMap::from([
+                ("id".to_string(), "1".to_string()),
+                ("version".to_string(), "3".to_string()),
+                ("sign".to_string(), "-1".to_string()),
+            ]))]
+        );
+        let update = create_keyed_change(
+            Operation::Update,
+            vec![Field {
+                name: "version".into(),
+                old_value: "3".into(),
+                new_value: "4".into(),
+            }],
+        );
+        assert_eq!(
+            change_mutations(&strategy, None, update, 10),
+            vec![
+                Mutation::InsertTombstone(HashMap::from([
+                    ("id".to_string(), "1".to_string()),
+                    ("version".to_string(), "3".to_string()),
+                    ("sign".to_string(), "-1".to_string()),
+                ])),
+                Mutation::Insert(HashMap::from([
+                    ("id".to_string(), "1".to_string()),
+                    ("version".to_string(), "4".to_string()),
+                    ("sign".to_string(), "1".to_string()),
+                ])),
+            ]
+        );
+    }
+
+    #[derive(Row, Debug, Deserialize, PartialEq)]
+    #[allow(dead_code)]
+    struct TestCollapsingInsert {
+        id: u64,
+        value: String,
+        sign: i8,
+    }
+
+    #[tokio::test]
+    async fn test_process_collapsing_data() -> Result<()> {
+        let mut mock = test::Mock::new();
+        mock.non_exhaustive();
+        let client = Client::default().with_url(mock.url());
+        let table = vec![DynamicTable::new(
+            "test",
+            vec![
+                ColumnInfo {
+                    column_name: "id".into(),
+                    data_type: ColumnType::UInt64,
+                },
+                ColumnInfo {
+                    column_name: "value".into(),
+                    data_type: ColumnType::String,
+                },
+                ColumnInfo {
+                    column_name: "sign".into(),
+                    data_type: ColumnType::Int8,
+                },
+            ],
+        )
+        .with_mutation_strategy(MutationStrategy::Collapsing {
+            sign: "sign".into(),
+            version: None,
+        })];
+        let mut loader = DatabaseLoader::new("test".into(), client, table);
+        let changes = vec![
+            create_keyed_change(
+                Operation::Update,
+                vec![Field {
+                    name: "value".into(),
+                    old_value: "a".into(),
+                    new_value: "b".into(),
+                }],
+            ),
+            create_keyed_change(Operation::Delete, vec![]),
+        ];
+        let data = create_block_scoped_data(changes);
+        let inserts_recording = mock.add(test::handlers::record());
+        loader.process_final_blocks(data).await?;
+        loader.end().await;
+        let inserts: Vec<TestCollapsingInsert> = inserts_recording.collect().await;
+        assert_eq!(
+            inserts,
+            vec![
+                TestCollapsingInsert {
+                    id: 1,
+                    value: "a".into(),
+                    sign: -1,
+                },
+                TestCollapsingInsert {
+                    id: 1,
+                    value: "b".into(),
+                    sign: 1,
+                },
+                TestCollapsingInsert {
+                    id: 1,
+                    value: "".into(),
+                    sign: -1,
+                },
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_pk_mutations() {
+        let mut change = TableChange {
+            table: "test".into(),
+            primary_key: Some(PrimaryKey::Pk("0xabc".into())),
+            fields: vec![Field {
+                name: "value".into(),
+                new_value: "1".into(),
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        change.set_operation(Operation::Create);
+        assert_eq!(
+            change_mutations(
+                &MutationStrategy::Insert,
+                Some("address"),
+                change.clone(),
+                10
+            ),
+            vec![Mutation::Insert(HashMap::from([
+                ("address".to_string(), "0xabc".to_string()),
+                ("value".to_string(), "1".to_string()),
+            ]))]
+        );
+        // the key column is sent by the module
+        assert_eq!(
+            change_mutations(&MutationStrategy::Insert, Some("value"), change, 10),
+            vec![Mutation::Insert(HashMap::from([(
+                "value".to_string(),
+                "1".to_string()
+            )]))]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_cancel_collapsing_rows() -> Result<()> {
+        let mut mock = test::Mock::new();
+        mock.non_exhaustive();
+        let client = Client::default().with_url(mock.url());
+        let table = DynamicTable::new(
+            "test",
+            vec![
+                ColumnInfo {
+                    column_name: "id".into(),
+                    data_type: ColumnType::UInt64,
+                },
+                ColumnInfo {
+                    column_name: "value".into(),
+                    data_type: ColumnType::String,
+                },
+                ColumnInfo {
+                    column_name: "sign".into(),
+                    data_type: ColumnType::Int8,
+                },
+            ],
+        )
+        .with_mutation_strategy(MutationStrategy::Collapsing {
+            sign: "sign".into(),
+            version: None,
+        });
+        let mut loader = DatabaseLoader::new("test".into(), client, vec![table.clone()])
+            .with_undo_mode(UndoMode::Reorg {
+                block_num_column: "block_num".into(),
+            });
+        let changes = vec![
+            // written without a value, it failed conversion and was never written
+            create_keyed_change(Operation::Create, vec![]),
+            create_keyed_change(
+                Operation::Update,
+                vec![Field {
+                    name: "value".into(),
+                    old_value: "a".into(),
+                    new_value: "b".into(),
+                }],
+            ),
+        ];
+        let inserts_recording = mock.add(test::handlers::record());
+        loader
+            .cancel_collapsing_rows(&table, "sign", changes, 10)
+            .await?;
+        loader.end().await;
+        let inserts: Vec<TestCollapsingInsert> = inserts_recording.collect().await;
+        assert_eq!(
+            inserts,
+            vec![
+                TestCollapsingInsert {
+                    id: 1,
+                    value: "b".into(),
+                    sign: -1,
+                },
+                TestCollapsingInsert {
+                    id: 1,
+                    value: "a".into(),
+                    sign: 1,
+                },
+            ]
+        );
+        Ok(())
+    }
+
+    fn create_undo_signal(block_num: u64) -> BlockUndoSignal {
+        BlockUndoSignal {
+            last_valid_block: Some(BlockRef {
+                id: "valid".into(),
+                number: block_num,
+            }),
+            last_valid_cursor: "cursor".into(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_undo_reorg_after_restart() -> Result<()> {
+        let mut mock = test::Mock::new();
+        mock.non_exhaustive();
+        let client = Client::default().with_url(mock.url());
+        let table = DynamicTable::new(
+            "test",
+            vec![ColumnInfo {
+                column_name: "block_num".into(),
+                data_type: ColumnType::UInt64,
+            }],
+        );
+        // nothing is buffered, the undone blocks were written before the restart
+        let mut loader = DatabaseLoader::new("test".into(), client, vec![table])
+            .with_undo_mode(UndoMode::Reorg {
+                block_num_column: "block_num".into(),
+            })
+            .with_persisted_block(Some(10));
+        let delete_recording = mock.add(test::handlers::record_ddl());
+        mock.add(test::handlers::record_ddl());
+        mock.add(test::handlers::record());
+        loader.process_undo_signal(create_undo_signal(8)).await?;
+        assert!(delete_recording
+            .query()
+            .await
+            .contains("DELETE FROM `test` WHERE `block_num` > 8"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_undo_collapsing_after_restart() {
+        let client = Client::default();
+        let table = DynamicTable::new("test", vec![]).with_mutation_strategy(
+            MutationStrategy::Collapsing {
+                sign: "sign".into(),
+                version: None,
+            },
+        );
+        let mut loader = DatabaseLoader::new("test".into(), client, vec![table])
+            .with_undo_mode(UndoMode::Reorg {
+                block_num_column: "block_num".into(),
+            })
+            .with_persisted_block(Some(10));
+        assert!(matches!(
+            loader.process_undo_signal(create_undo_signal(8)).await,
+            Err(ElricError::UnbufferedUndo(_, 8))
+        ));
+    }
+
+    #[test]
+    fn test_check_block_num_column() {
+        let block_num = ColumnInfo {
+            column_name: "block_num".into(),
+            data_type: ColumnType::UInt64,
+        };
+        let loader = DatabaseLoader::new("test".into(), Client::default(), vec![]);
+        let table = DynamicTable::new("test", vec![]);
+        assert!(loader.check_block_num_column(&table).is_ok());
+
+        let loader = loader.with_undo_mode(UndoMode::Reorg {
+            block_num_column: "block_num".into(),
+        });
+        assert!(matches!(
+            loader.check_block_num_column(&table),
+            Err(ElricError::MissingBlockNumColumn(..))
+        ));
+        let table = DynamicTable::new("test", vec![block_num]);
+        assert!(loader.check_block_num_column(&table).is_ok());
+        let table = DynamicTable::new("test", vec![]).with_mutation_strategy(
+            MutationStrategy::Collapsing {
+                sign: "sign".into(),
+                version: None,
+            },
+        );
+        assert!(loader.check_block_num_column(&table).is_ok());
+    }
+
+    fn create_invalid_changes() -> Vec<TableChange> {
+        vec![
+            TableChange {
+                table: "test".into(),
+                fields: vec![Field {
+                    name: "test".into(),
+                    new_value: "1.0".into(),
+                    ..Default::default()
+                }],
+                ..Default::default()
+            },
+            TableChange {
+                table: "test".into(),
+                fields: vec![Field {
+                    name: "test".into(),
+                    new_value: "2".into(),
+                    ..Default::default()
+                }],
+                ..Default::default()
+            },
+        ]
+    }
+
+    #[tokio::test]
+    async fn test_process_invalid_data_skip() -> Result<()> {
+        let mut mock = test::Mock::new();
+        mock.non_exhaustive();
+        let client = Client::default().with_url(mock.url());
+        let table = vec![DynamicTable::new(
+            "test",
+            vec![ColumnInfo {
+                column_name: "test".into(),
+                data_type: ColumnType::UInt64,
+            }],
+        )];
+        let mut loader =
+            DatabaseLoader::new("test".into(), client, table).with_error_policy(ErrorPolicy::Skip);
+        let data = create_block_scoped_data(create_invalid_changes());
+        let inserts_recording = mock.add(test::handlers::record());
+        loader.process_final_blocks(data).await?;
+        loader.end().await;
+        let inserts: Vec<TestInsert> = inserts_recording.collect().await;
+        assert_eq!(inserts, vec![TestInsert { test: 2 }]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_process_invalid_data_dead_letter() -> Result<()> {
+        let mut mock = test::Mock::new();
+        mock.non_exhaustive();
+        let client = Client::default().with_url(mock.url());
+        let mut loader = DatabaseLoader::new("test".into(), client, vec![])
+            .with_error_policy(ErrorPolicy::DeadLetter("dead_letters".into()));
+        let changes = vec![TableChange {
+            table: "unknown".into(),
+            fields: vec![Field {
+                name: "test".into(),
+                new_value: "1".into(),
+                ..Default::default()
+            }],
+            ..Default::default()
+        }];
+        let data = create_block_scoped_data(changes);
+        // the unknown table is not found by the schema refresh either
+        mock.add(test::handlers::provide(Vec::<TestTableInfo>::new()));
+        let dead_letter_recording = mock.add(test::handlers::record());
+        loader.process_final_blocks(data).await?;
+        loader.end().await;
+        let dead_letters: Vec<DeadLetter> = dead_letter_recording.collect().await;
+        assert_eq!(
+            dead_letters,
+            vec![DeadLetter {
+                id: "test".into(),
+                block_num: 0,
+                block_id: "".into(),
+                table: "unknown".into(),
+                fields: r#"{"test":"1"}"#.into(),
+                error: "Could not find table unknown".into(),
+            }]
+        );
+        Ok(())
+    }
+
     fn create_block_scoped_data(table_changes: Vec<TableChange>) -> BlockScopedData {
         let mut buffer = vec![];
         let _ = DatabaseChanges { table_changes }.encode(&mut buffer);
@@ -1,7 +1,6 @@
-use anyhow::Error;
+use anyhow::{anyhow, Error};
 use clap::{Parser, Subcommand};
 use clickhouse::Client;
-use futures03::future::join_all;
 use futures03::StreamExt;
 use hyper_rustls::HttpsConnectorBuilder;
 use loader::Cursor;
@@ -15,6 +14,7 @@ use url::Url;
 use prost::Message;
 use std::collections::VecDeque;
 use std::fs;
+use std::num::NonZeroUsize;
 use std::{collections::HashMap, env, process::exit, sync::Arc, time::Duration};
 use substreams::SubstreamsEndpoint;
 use substreams_database_change::pb::database::Field;
@@ -24,9 +24,18 @@ use tokio::select;
 use tokio::signal::unix::{signal, SignalKind};
 use tokio::sync::watch;
 
-use crate::loader::DatabaseLoader;
-use crate::table_info::{get_columns, get_table_information, DynamicTable};
-
+use crate::loader::{
+    check_cursors_table, create_dead_letter_table, is_internal_table, load_dynamic_tables,
+    setup_cursors_table, CursorRetention, CursorTable, DatabaseLoader, ErrorPolicy, FlushPolicy,
+    UndoMode, BUFFER_LEN,
+};
+use crate::table_info::{
+    parse_table_inserter_settings, parse_table_key_column, parse_table_mutation_strategy,
+    ConversionOptions, InserterSettings, MutationStrategy, TableSettings,
+};
+use crate::validate::validate_module;
+
+mod convert;
 mod fixed_string;
 mod loader;
 mod logging;
@@ -34,6 +43,7 @@ mod pb;
 mod substreams;
 mod substreams_stream;
 mod table_info;
+mod validate;
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
@@ -63,11 +73,202 @@ enum Commands {
         start_block: i64,
         #[arg(long, default_value = "0")]
         end_block: u64,
+        /// Delimiter used to split Array fields that are not a JSON array
+        #[arg(long)]
+        array_delimiter: Option<String>,
+        /// Decode hex values into raw bytes for the String and FixedString columns
+        /// of a table (`table`) or of a single column (`table.column`)
+        #[arg(long)]
+        hex_decode: Vec<String>,
+        /// What to do with changes that can't be inserted: `fail`, `skip`,
+        /// `dead-letter` or `dead-letter:<table>`
+        #[arg(long, default_value = "fail")]
+        error_policy: ErrorPolicy,
+        /// How UPDATE and DELETE changes of a table are written, as `<table>=<strategy>`
+        /// where strategy is `insert`, `replacing:<version>[:<is_deleted>]`,
+        /// `collapsing:<sign>[:<version>]` or `lightweight-delete`
+        #[arg(long, value_parser = parse_table_mutation_strategy)]
+        mutation_strategy: Vec<(String, MutationStrategy)>,
+        /// Column receiving the single-column primary key of the changes of a table,
+        /// as `<table>=<column>`. Defaults to the primary key of the table when it
+        /// is a single column
+        #[arg(long, value_parser = parse_table_key_column)]
+        key_column: Vec<(String, String)>,
+        /// Write blocks as soon as they are received instead of waiting for them to be
+        /// final, undoing the blocks of a reorg in ClickHouse
+        #[arg(long, conflicts_with = "final_blocks_only")]
+        reorg: bool,
+        /// Column holding the block number of the rows, used to delete the rows of
+        /// undone blocks in reorg mode
+        #[arg(long, default_value = "block_num")]
+        block_num_column: String,
+        /// Number of non-final blocks kept in memory before being written
+        #[arg(long, default_value_t = NonZeroUsize::new(BUFFER_LEN).unwrap())]
+        buffer_size: NonZeroUsize,
+        /// Only request final blocks, written as soon as they are received
+        #[arg(long)]
+        final_blocks_only: bool,
+        /// Name of the table where cursors are persisted
+        #[arg(long, default_value = "cursors")]
+        cursor_table: String,
+        /// Database of the cursors table, defaults to the database of the data tables
+        #[arg(long)]
+        cursor_database: Option<String>,
+        /// Number of latest cursors kept per id when cleaning up the cursors table
+        #[arg(long)]
+        cursor_keep_last: Option<NonZeroUsize>,
+        /// Age in seconds after which cursors are deleted when cleaning up the cursors
+        /// table, the latest cursor being always kept
+        #[arg(long)]
+        cursor_ttl: Option<u64>,
+        /// Merge the parts of the cursors table with `OPTIMIZE TABLE ... FINAL` when
+        /// cleaning it up
+        #[arg(long)]
+        cursor_optimize: bool,
+        /// Number of persisted cursors between two cleanups of the cursors table
+        #[arg(long, default_value = "10000")]
+        cursor_cleanup_interval: u64,
+        /// Insert each block in its own INSERT tagged with an `insert_deduplication_token`,
+        /// so blocks replayed after a crash are not duplicated. Rows can't be batched
+        /// across blocks with it.
+        #[arg(
+            long,
+            conflicts_with_all = [
+                "reorg",
+                "flush_max_rows",
+                "flush_max_bytes",
+                "flush_max_blocks",
+                "flush_max_time",
+            ]
+        )]
+        deduplicate: bool,
+        /// Flush the rows written across blocks once this number of rows is reached
+        #[arg(long)]
+        flush_max_rows: Option<u64>,
+        /// Flush the rows written across blocks once the blocks payloads reach this size
+        #[arg(long)]
+        flush_max_bytes: Option<u64>,
+        /// Flush the rows written across blocks once this number of blocks is reached
+        #[arg(long)]
+        flush_max_blocks: Option<u64>,
+        /// Flush the rows written across blocks after this number of seconds
+        #[arg(long)]
+        flush_max_time: Option<u64>,
+        /// Timeout in seconds of each chunk sent by the inserters [default: 5]
+        #[arg(long)]
+        inserter_send_timeout: Option<u64>,
+        /// Timeout in seconds of the end of an insert [default: 20]
+        #[arg(long)]
+        inserter_end_timeout: Option<u64>,
+        /// Number of seconds after which the inserters end their current insert [default: 15]
+        #[arg(long)]
+        inserter_period: Option<u64>,
+        /// Number of rows after which the inserters end their current insert
+        #[arg(long)]
+        inserter_max_entries: Option<u64>,
+        /// Number of bytes after which the inserters end their current insert
+        #[arg(long)]
+        inserter_max_bytes: Option<u64>,
+        /// Inserter settings of a table, as `<table>=<setting>=<value>[,...]` where
+        /// setting is `send-timeout`, `end-timeout`, `period`, `max-entries` or `max-bytes`
+        #[arg(long, value_parser = parse_table_inserter_settings)]
+        table_inserter: Vec<(String, InserterSettings)>,
+        /// Number of seconds between two refreshes of the tables schema, which is
+        /// otherwise only refreshed when an unknown table or column shows up
+        #[arg(long)]
+        schema_refresh_interval: Option<u64>,
+        /// Check the module against the tables on the given number of blocks before
+        /// starting, exiting if they are not compatible
+        #[arg(long)]
+        validate_blocks: Option<u64>,
     },
     Setup {
         database_url: Url,
         file_name: String,
     },
+    /// Check that the output of a module can be loaded into the tables, exiting
+    /// with an error if it can't
+    Validate {
+        database_url: Url,
+        #[arg(long, default_value = "substreams.spkg")]
+        package_file: String,
+        #[arg(long, default_value = "db_out")]
+        module: String,
+        #[arg(
+            long,
+            short,
+            default_value = "https://mainnet.eth.streamingfast.io:443"
+        )]
+        endpoint_url: String,
+        #[arg(long)]
+        token: Option<String>,
+        #[arg(long, default_value = "0")]
+        start_block: i64,
+        /// Number of blocks of the module output checked against the tables, 0 only
+        /// checking the package
+        #[arg(long, default_value = "10")]
+        blocks: u64,
+        /// Delimiter used to split Array fields that are not a JSON array
+        #[arg(long)]
+        array_delimiter: Option<String>,
+        /// Decode hex values into raw bytes for the String and FixedString columns
+        /// of a table (`table`) or of a single column (`table.column`)
+        #[arg(long)]
+        hex_decode: Vec<String>,
+        /// How UPDATE and DELETE changes of a table are written, as `<table>=<strategy>`
+        #[arg(long, value_parser = parse_table_mutation_strategy)]
+        mutation_strategy: Vec<(String, MutationStrategy)>,
+        /// Column receiving the single-column primary key of the changes of a table,
+        /// as `<table>=<column>`
+        #[arg(long, value_parser = parse_table_key_column)]
+        key_column: Vec<(String, String)>,
+        /// Name of the table where cursors are persisted, which is not validated
+        #[arg(long, default_value = "cursors")]
+        cursor_table: String,
+        /// Database of the cursors table, defaults to the database of the url
+        #[arg(long)]
+        cursor_database: Option<String>,
+        /// Error policy of the sink, whose dead-letter table is not validated
+        #[arg(long, default_value = "fail")]
+        error_policy: ErrorPolicy,
+    },
+    /// Inspect and change the persisted cursors
+    Cursor {
+        database_url: Url,
+        /// Name of the table where cursors are persisted
+        #[arg(long, default_value = "cursors")]
+        cursor_table: String,
+        /// Database of the cursors table, defaults to the database of the url
+        #[arg(long)]
+        cursor_database: Option<String>,
+        #[command(subcommand)]
+        command: CursorCommands,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+enum CursorCommands {
+    /// Print the cursor a sink restarts from
+    Get { id: String },
+    /// Set the cursor a sink restarts from, either a given cursor or the cursor
+    /// persisted at or below a block
+    Set {
+        id: String,
+        #[arg(long, required_unless_present = "block", requires = "block")]
+        cursor: Option<String>,
+        /// Block of the given cursor, or block to rewind to without `--cursor`
+        #[arg(long)]
+        block: Option<u64>,
+        /// Block id of the given cursor
+        #[arg(long, default_value = "", requires = "cursor")]
+        block_id: String,
+    },
+    /// Delete the cursors of a sink, which restarts from its start block
+    Delete { id: String },
+    /// Print the latest cursor of every sink
+    List,
+    /// Print the cursors of a sink that were not cleaned up
+    History { id: String },
 }
 
 #[derive(Error, Debug)]
@@ -88,8 +289,40 @@ pub enum ElricError {
     InsertRowError,
     #[error("Could not commit transaction")]
     CommitError,
+    #[error("Could not find table {0}")]
+    TableNotFound(String),
+    #[error("Could not insert dead letter")]
+    InsertDeadLetterError,
+    #[error("Could not delete row: {0}")]
+    DeleteRowError(clickhouse::error::Error),
+    #[error("Missing primary key of a change of table {0}")]
+    MissingPrimaryKey(String),
+    #[error("Could not undo blocks of table {0} without a {1} column")]
+    MissingBlockNumColumn(String, String),
+    #[error("Could not undo Collapsing table {0} above block {1}, written before the restart")]
+    UnbufferedUndo(String, u64),
+    #[error("Could not undo to block {0}, blocks up to {1} are already written")]
+    UndoTooDeep(u64, u64),
+    #[error("Invalid cursors table: {0}")]
+    InvalidCursorsTable(String),
+    #[error("Stream terminated with error: {0}")]
+    StreamError(anyhow::Error),
     #[error("Could not find columns for database {0} table {1}")]
     ColumnNotFound(String, String),
+    #[error("Unsupported column type {0}")]
+    UnsupportedColumnType(String),
+    #[error(
+        "Could not convert value {} of column {table}.{column} ({data_type}) at block {block_num}: {source}",
+        .value.as_deref().unwrap_or("NULL")
+    )]
+    ConvertFieldError {
+        table: String,
+        column: String,
+        data_type: String,
+        value: Option<String>,
+        block_num: u64,
+        source: convert::ConversionError,
+    },
 }
 
 #[tokio::main]
@@ -114,6 +347,81 @@ async fn main() -> Result<(), Error> {
             setup_schema(&client, file_name).await?;
             info!("Schema setup complete");
         }
+        Commands::Validate {
+            database_url,
+            package_file,
+            module,
+            endpoint_url,
+            token,
+            start_block,
+            blocks,
+            array_delimiter,
+            hex_decode,
+            mutation_strategy,
+            key_column,
+            cursor_table,
+            cursor_database,
+            error_policy,
+        } => {
+            let client = load_database(database_url);
+            let cursor_table = CursorTable {
+                database: cursor_database,
+                name: cursor_table,
+            };
+            let token = env::var("SUBSTREAMS_API_TOKEN").ok().or(token);
+            let endpoint = Arc::new(SubstreamsEndpoint::new(endpoint_url, token));
+            let stream = create_stream(
+                None,
+                package_file.clone(),
+                module.clone(),
+                endpoint,
+                start_block,
+                0,
+                false,
+            )?;
+            let table_settings = TableSettings {
+                options: ConversionOptions {
+                    array_delimiter,
+                    ..Default::default()
+                },
+                hex_decode,
+                mutation_strategies: mutation_strategy.into_iter().collect(),
+                key_columns: key_column.into_iter().collect(),
+                ..Default::default()
+            };
+            let tables = load_dynamic_tables(&client, &table_settings, |table| {
+                !is_internal_table(table, &cursor_table, &error_policy)
+            })
+            .await?;
+            let package = read_package(&package_file)?;
+            let report = validate_module(&package, &module, tables, stream, blocks).await?;
+            println!("{}", report);
+            if !report.is_compatible() {
+                exit(1);
+            }
+        }
+        Commands::Cursor {
+            database_url,
+            cursor_table,
+            cursor_database,
+            command,
+        } => {
+            let client = load_database(database_url);
+            let cursor_table = CursorTable {
+                database: cursor_database,
+                name: cursor_table,
+            };
+            // only setting a cursor creates the table, the other commands report it missing
+            let retention = CursorRetention::default();
+            if matches!(command, CursorCommands::Set { .. }) {
+                setup_cursors_table(&client, &cursor_table, &retention).await?;
+            } else {
+                check_cursors_table(&client, &cursor_table, &retention).await?;
+            }
+            run_cursor_command(&client, &cursor_table, command)
+                .await
+                .map_err(ElricError::CursorError)?;
+        }
         Commands::Run {
             id,
             database_url,
@@ -123,49 +431,169 @@ async fn main() -> Result<(), Error> {
             token,
             start_block,
             end_block,
+            array_delimiter,
+            hex_decode,
+            error_policy,
+            mutation_strategy,
+            key_column,
+            reorg,
+            block_num_column,
+            buffer_size,
+            final_blocks_only,
+            cursor_table,
+            cursor_database,
+            cursor_keep_last,
+            cursor_ttl,
+            cursor_optimize,
+            cursor_cleanup_interval,
+            deduplicate,
+            flush_max_rows,
+            flush_max_bytes,
+            flush_max_blocks,
+            flush_max_time,
+            inserter_send_timeout,
+            inserter_end_timeout,
+            inserter_period,
+            inserter_max_entries,
+            inserter_max_bytes,
+            table_inserter,
+            schema_refresh_interval,
+            validate_blocks,
         } => {
             let client = load_database(database_url);
             let token = match env::var("SUBSTREAMS_API_TOKEN").ok() {
                 Some(token) => token,
                 None => token.ok_or(ElricError::TokenNotFound)?,
             };
-            let cursor = load_persisted_cursor(&client, &id)
+            let cursor_table = CursorTable {
+                database: cursor_database,
+                name: cursor_table,
+            };
+            let cursor_retention = CursorRetention {
+                keep_last: cursor_keep_last,
+                ttl: cursor_ttl.map(Duration::from_secs),
+                optimize: cursor_optimize,
+                interval: cursor_cleanup_interval,
+            };
+            setup_cursors_table(&client, &cursor_table, &cursor_retention).await?;
+            let persisted_cursor = load_persisted_cursor(&client, &cursor_table, &id)
                 .await
                 .map_err(|e| ElricError::CursorError(e))?;
+            let persisted_block = persisted_cursor.as_ref().map(Cursor::block_num);
+            let cursor = persisted_cursor.map(|c| c.cursor().clone());
+            let endpoint = Arc::new(SubstreamsEndpoint::new(endpoint_url, Some(token)));
+            let table_settings = TableSettings {
+                options: ConversionOptions {
+                    array_delimiter,
+                    ..Default::default()
+                },
+                hex_decode,
+                mutation_strategies: mutation_strategy.into_iter().collect(),
+                key_columns: key_column.into_iter().collect(),
+                inserter_settings: table_inserter.into_iter().collect(),
+            };
+            if let Some(blocks) = validate_blocks {
+                let sample = create_stream(
+                    cursor.clone(),
+                    package_file.clone(),
+                    module_name.clone(),
+                    endpoint.clone(),
+                    start_block,
+                    end_block,
+                    final_blocks_only,
+                )?;
+                let tables = load_dynamic_tables(&client, &table_settings, |table| {
+                    !is_internal_table(table, &cursor_table, &error_policy)
+                })
+                .await?;
+                let package = read_package(&package_file)?;
+                let report =
+                    validate_module(&package, &module_name, tables, sample, blocks).await?;
+                if !report.is_compatible() {
+                    error!("{}", report);
+                    exit(1);
+                }
+                info!("{}", report);
+            }
             let stream = create_stream(
                 cursor,
                 package_file,
                 module_name,
-                endpoint_url,
-                token,
+                endpoint,
                 start_block,
                 end_block,
+                final_blocks_only,
             )?;
-            run(id, stream, client).await?;
+            let undo_mode = if reorg {
+                UndoMode::Reorg { block_num_column }
+            } else if final_blocks_only {
+                UndoMode::FinalBlocksOnly
+            } else {
+                UndoMode::Buffer
+            };
+            let loader_settings = LoaderSettings {
+                error_policy,
+                undo_mode,
+                buffer_len: buffer_size.get(),
+                cursor_table,
+                cursor_retention,
+                persisted_block,
+                deduplicate,
+                flush_policy: FlushPolicy {
+                    max_rows: flush_max_rows,
+                    max_bytes: flush_max_bytes,
+                    max_blocks: flush_max_blocks,
+                    max_time: flush_max_time.map(Duration::from_secs),
+                },
+                inserter_settings: InserterSettings {
+                    send_timeout: inserter_send_timeout.map(Duration::from_secs),
+                    end_timeout: inserter_end_timeout.map(Duration::from_secs),
+                    period: inserter_period.map(Duration::from_secs),
+                    max_entries: inserter_max_entries,
+                    max_bytes: inserter_max_bytes,
+                },
+                schema_refresh_interval: schema_refresh_interval.map(Duration::from_secs),
+            };
+            run(id, stream, client, table_settings, loader_settings).await?;
         }
     }
     Ok(())
 }
 
+/// Loader settings given on the command line
+struct LoaderSettings {
+    error_policy: ErrorPolicy,
+    undo_mode: UndoMode,
+    buffer_len: usize,
+    cursor_table: CursorTable,
+    cursor_retention: CursorRetention,
+    /// Block of the cursor the sink restarts from
+    persisted_block: Option<u64>,
+    deduplicate: bool,
+    flush_policy: FlushPolicy,
+    inserter_settings: InserterSettings,
+    schema_refresh_interval: Option<Duration>,
+}
+
 fn create_stream(
     cursor: Option<String>,
     package_file: String,
     module: String,
-    endpoint_url: String,
-    token: String,
+    endpoint: Arc<SubstreamsEndpoint>,
     start_block: i64,
     end_block: u64,
+    final_blocks_only: bool,
 ) -> Result<SubstreamsStream, ElricError> {
     let package = read_package(&package_file)?;
-    let endpoint = Arc::new(SubstreamsEndpoint::new(endpoint_url, Some(token)));
 
     Ok(SubstreamsStream::new(
-        endpoint.clone(),
+        endpoint,
         cursor,
         package.modules.clone(),
         module,
         start_block,
         end_block,
+        final_blocks_only,
     ))
 }
 
@@ -173,23 +601,38 @@ async fn run(
     id: String,
     mut stream: SubstreamsStream,
     client: clickhouse::Client,
+    table_settings: TableSettings,
+    loader_settings: LoaderSettings,
 ) -> Result<(), ElricError> {
-    let table_info = get_table_information(&client).await?;
-
-    let dynamic_tables = table_info
-        .iter()
-        .map(|table| async {
-            let mut columns = get_columns(&client, &table.table_schema, &table.table_name).await?;
-            columns.sort();
-            Ok(DynamicTable::new(&table.table_name, columns))
-        })
-        .collect::<Vec<_>>();
-    let dynamic_tables = join_all(dynamic_tables)
-        .await
-        .into_iter()
-        .collect::<Result<Vec<_>, ElricError>>()?;
-
-    let mut loader = DatabaseLoader::new(id, client, dynamic_tables);
+    let LoaderSettings {
+        error_policy,
+        undo_mode,
+        buffer_len,
+        cursor_table,
+        cursor_retention,
+        persisted_block,
+        deduplicate,
+        flush_policy,
+        inserter_settings,
+        schema_refresh_interval,
+    } = loader_settings;
+    if let ErrorPolicy::DeadLetter(table) = &error_policy {
+        create_dead_letter_table(&client, table).await?;
+    }
+
+    let mut loader = DatabaseLoader::new(id, client, vec![])
+        .with_table_settings(table_settings)
+        .with_schema_refresh_interval(schema_refresh_interval)
+        .with_inserter_settings(inserter_settings)
+        .with_error_policy(error_policy)
+        .with_undo_mode(undo_mode)
+        .with_buffer_len(buffer_len)
+        .with_cursor_table(cursor_table)
+        .with_cursor_retention(cursor_retention)
+        .with_persisted_block(persisted_block)
+        .with_deduplication(deduplicate)
+        .with_flush_policy(flush_policy);
+    loader.refresh_schema().await?;
 
     let (stop_tx, mut stop_rx) = watch::channel(());
 
@@ -219,8 +662,7 @@ async fn run(
                     loader.process_block_scoped_data(data).await?;
                 }
                 Some(Ok(BlockResponse::Undo(undo_signal))) => {
-                    let block_num_signal = undo_signal.last_valid_block.as_ref().unwrap().number;
-                    loader.process_block_undo_signal(block_num_signal);
+                    loader.process_undo_signal(undo_signal).await?;
                 }
                 Some(Err(err)) => {
                     error!(%err, "Stream terminated with error");
@@ -296,17 +738,69 @@ fn convert_field_to_hash(fields: Vec<Field>) -> HashMap<String, String> {
     field_map
 }
 
+async fn run_cursor_command(
+    client: &Client,
+    cursor_table: &CursorTable,
+    command: CursorCommands,
+) -> Result<(), Error> {
+    match command {
+        CursorCommands::Get { id } => {
+            let cursor = cursor_table
+                .latest(client, &id)
+                .await?
+                .ok_or_else(|| anyhow!("no cursor for {}", id))?;
+            println!("{}", cursor);
+        }
+        CursorCommands::Set {
+            id,
+            cursor: Some(cursor),
+            block,
+            block_id,
+        } => {
+            let cursor = Cursor::new(id, cursor, block.unwrap_or_default(), block_id);
+            cursor_table.set(client, cursor).await?;
+            info!("Cursor set");
+        }
+        CursorCommands::Set {
+            id,
+            cursor: None,
+            block,
+            ..
+        } => {
+            let block = block.unwrap_or_default();
+            let cursor = cursor_table
+                .at_block(client, &id, block)
+                .await?
+                .ok_or_else(|| anyhow!("no cursor for {} at or below block {}", id, block))?;
+            cursor_table
+                .delete(client, &id, Some(cursor.block_num()))
+                .await?;
+            println!("{}", cursor);
+        }
+        CursorCommands::Delete { id } => {
+            cursor_table.delete(client, &id, None).await?;
+            info!("Cursor deleted");
+        }
+        CursorCommands::List => {
+            for cursor in cursor_table.list(client).await? {
+                println!("{}", cursor);
+            }
+        }
+        CursorCommands::History { id } => {
+            for cursor in cursor_table.history(client, &id).await? {
+                println!("{}", cursor);
+            }
+        }
+    }
+    Ok(())
+}
+
 async fn load_persisted_cursor(
     client: &clickhouse::Client,
+    cursor_table: &CursorTable,
     id: &str,
-) -> Result<Option<String>, anyhow::Error> {
-    let cursor = client.query(&format!(
-        "SELECT * FROM cursors WHERE id = '{}' ORDER BY block_num DESC",
-        id
-    ));
-    let cursor = cursor.fetch_optional::<Cursor>().await?;
-
-    Ok(cursor.map(|c| c.cursor().clone()))
+) -> Result<Option<Cursor>, anyhow::Error> {
+    Ok(cursor_table.latest(client, id).await?)
 }
 
 fn read_package(file: &str) -> Result<Package, ElricError> {
@@ -317,9 +811,10 @@ fn read_package(file: &str) -> Result<Package, ElricError> {
 #[cfg(test)]
 mod tests {
 
-    use clickhouse::Row;
+    use clickhouse::{test, Client, Row};
     use serde::Serialize;
 
+    use super::{load_persisted_cursor, CursorTable};
     // use super::*;
 
     #[derive(Row, Serialize)]
@@ -327,6 +822,20 @@ mod tests {
         contract: String,
     }
 
+    #[tokio::test]
+    async fn test_load_persisted_cursor_hostile_id() {
+        let mock = test::Mock::new();
+        let client = Client::default().with_url(mock.url());
+        let recording = mock.add(test::handlers::record_ddl());
+        let id = "x' OR '1'='1";
+        let cursor = load_persisted_cursor(&client, &CursorTable::default(), id)
+            .await
+            .unwrap();
+        assert_eq!(cursor, None);
+        let query = recording.query().await;
+        assert!(query.contains(r"WHERE id = 'x\' OR \'1\'=\'1' ORDER BY"));
+    }
+
     // #[test]
     // fn check_encoders() -> Result<()> {
     //     let mut buffer = BytesMut::new();
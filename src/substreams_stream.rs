@@ -35,6 +35,7 @@ impl SubstreamsStream {
         output_module_name: String,
         start_block: i64,
         end_block: u64,
+        final_blocks_only: bool,
     ) -> Self {
         SubstreamsStream {
             stream: Box::pin(stream_blocks(
@@ -44,6 +45,7 @@ impl SubstreamsStream {
                 output_module_name,
                 start_block,
                 end_block,
+                final_blocks_only,
             )),
         }
     }
@@ -57,6 +59,7 @@ fn stream_blocks(
     output_module_name: String,
     start_block_num: i64,
     stop_block_num: u64,
+    final_blocks_only: bool,
 ) -> impl Stream<Item = Result<BlockResponse, Error>> {
     let mut latest_cursor = cursor.unwrap_or_default();
     let mut backoff = ExponentialBackoff::from_millis(10).max_delay(Duration::from_secs(45));
@@ -73,7 +76,7 @@ fn stream_blocks(
                 start_block_num,
                 start_cursor: latest_cursor.clone(),
                 stop_block_num,
-                final_blocks_only: false,
+                final_blocks_only,
                 modules: modules.clone(),
                 output_module: output_module_name.clone(),
                 // There is usually no good reason for you to consume the stream development mode (so switching `true`
@@ -1,14 +1,29 @@
 use std::{
-    collections::{HashMap, VecDeque},
+    collections::{HashMap, HashSet},
+    fmt::Display,
+    net::{Ipv4Addr, Ipv6Addr},
     str::FromStr,
+    time::Duration,
 };
 
 use clickhouse::{schema::Schema, Client, Row};
 use primitive_types::U256;
-use serde::{ser::SerializeTuple, Deserialize, Serialize};
+use serde::{
+    ser::{SerializeSeq, SerializeTuple},
+    Deserialize, Serialize,
+};
 use strum_macros::EnumString;
+use thiserror::Error;
+use tracing::warn;
 
-use crate::ElricError;
+use crate::{
+    convert::{
+        decode_hex, fixed_string_bytes, parse_array, parse_bool, parse_datetime, parse_datetime64,
+        parse_days, parse_decimal, parse_enum, parse_float, parse_int256, parse_integer,
+        parse_ipv4, parse_ipv6, parse_map, parse_tuple, parse_uint256, parse_uuid, ConversionError,
+    },
+    ElricError,
+};
 
 #[derive(Debug, Clone, PartialEq, Default, PartialOrd, Eq, EnumString)]
 pub enum ColumnType {
@@ -29,31 +44,336 @@ pub enum ColumnType {
     Int256,
     Float32,
     Float64,
-    DateTime,
+    /// DateTime with an optional timezone
+    DateTime(Option<String>),
+    /// DateTime64 with precision and an optional timezone
+    DateTime64(u32, Option<String>),
     Date,
+    Date32,
     Bool,
-    LowCardinality,
-    Decimal,
+    LowCardinality(Box<ColumnType>),
+    /// Decimal with precision and scale
+    Decimal(u32, u32),
     Nullable(Box<ColumnType>),
+    Array(Box<ColumnType>),
+    /// Map with key and value types
+    Map(Box<ColumnType>, Box<ColumnType>),
+    /// Tuple elements with their optional names
+    Tuple(Vec<(Option<String>, ColumnType)>),
+    #[strum(serialize = "UUID")]
+    Uuid,
+    IPv4,
+    IPv6,
+    /// Enum8 with its name to value mapping
+    Enum8(Vec<(String, i8)>),
+    /// Enum16 with its name to value mapping
+    Enum16(Vec<(String, i16)>),
+    /// A type elric can't write, loading fails only if a value is sent for it
+    #[strum(disabled)]
+    Unsupported(String),
 }
 
+/// A row of a `DynamicTable`, with its fields already converted to the column types
 pub struct DynamicInsert {
-    data: HashMap<String, String>,
-    table_info: DynamicTable,
+    values: Vec<Value>,
+}
+
+/// A field that could not be converted to the type of its column
+#[derive(Error, Debug)]
+#[error("could not convert column {column} ({data_type}): {source}")]
+pub struct FieldConversionError {
+    pub column: String,
+    pub data_type: ColumnType,
+    pub value: Option<String>,
+    #[source]
+    pub source: ConversionError,
+}
+
+/// Options used to convert field values into column values
+#[derive(Debug, Clone, Default)]
+pub struct ConversionOptions {
+    /// Delimiter used to split `Array` fields that are not a JSON array
+    pub array_delimiter: Option<String>,
+    /// Columns whose `String` and `FixedString` values are hex decoded into raw bytes
+    pub hex_columns: HexColumns,
+}
+
+#[derive(Debug, Clone, Default)]
+pub enum HexColumns {
+    #[default]
+    None,
+    All,
+    Columns(HashSet<String>),
+}
+
+impl ConversionOptions {
+    /// Selects the hex columns of `table` out of `--hex-decode` values,
+    /// which are either a table name or a `table.column` pair
+    pub fn with_hex_decode(mut self, table: &str, hex_decode: &[String]) -> Self {
+        let mut columns = HashSet::new();
+        for value in hex_decode {
+            match value.split_once('.') {
+                Some((hex_table, column)) if hex_table == table => {
+                    columns.insert(column.to_string());
+                }
+                None if value == table => {
+                    self.hex_columns = HexColumns::All;
+                    return self;
+                }
+                _ => {}
+            }
+        }
+        if !columns.is_empty() {
+            self.hex_columns = HexColumns::Columns(columns);
+        }
+        self
+    }
+
+    fn is_hex_column(&self, column_name: &str) -> bool {
+        match &self.hex_columns {
+            HexColumns::None => false,
+            HexColumns::All => true,
+            HexColumns::Columns(columns) => columns.contains(column_name),
+        }
+    }
+}
+
+/// How the UPDATE and DELETE changes of a table are written
+#[derive(Debug, Clone, PartialEq, Default)]
+pub enum MutationStrategy {
+    /// Creates and updates are inserted as new rows, deletes are ignored
+    #[default]
+    Insert,
+    /// `ReplacingMergeTree(version, is_deleted)`: rows are inserted with the block
+    /// number as version, deletes are inserted with `is_deleted` set to 1
+    Replacing {
+        version: String,
+        is_deleted: Option<String>,
+    },
+    /// `CollapsingMergeTree(sign)` and `VersionedCollapsingMergeTree(sign, version)`:
+    /// an update cancels the `old_value` row with a -1 row before inserting the
+    /// new one with +1, a delete only writes the -1 row. The -1 rows of a
+    /// `VersionedCollapsingMergeTree` carry the version of the row they cancel.
+    Collapsing {
+        sign: String,
+        version: Option<String>,
+    },
+    /// Creates and updates are inserted, deletes run a lightweight `DELETE FROM`
+    /// on the primary key
+    LightweightDelete,
+}
+
+impl FromStr for MutationStrategy {
+    type Err = String;
+
+    /// Parses `insert`, `replacing:<version>[:<is_deleted>]`, `collapsing:<sign>[:<version>]`
+    /// or `lightweight-delete`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut args = s.split(':');
+        let strategy = match (args.next(), args.next(), args.next(), args.next()) {
+            (Some("insert"), None, ..) => MutationStrategy::Insert,
+            (Some("replacing"), Some(version), is_deleted, None) if !version.is_empty() => {
+                MutationStrategy::Replacing {
+                    version: version.to_string(),
+                    is_deleted: is_deleted.map(str::to_string),
+                }
+            }
+            (Some("collapsing"), Some(sign), version, None) if !sign.is_empty() => {
+                MutationStrategy::Collapsing {
+                    sign: sign.to_string(),
+                    version: version.map(str::to_string),
+                }
+            }
+            (Some("lightweight-delete"), None, ..) => MutationStrategy::LightweightDelete,
+            _ => return Err(format!("invalid mutation strategy {}", s)),
+        };
+        Ok(strategy)
+    }
+}
+
+/// Parses a `--key-column` value of the form `<table>=<column>`
+pub fn parse_table_key_column(s: &str) -> Result<(String, String), String> {
+    match s.split_once('=') {
+        Some((table, column)) if !table.is_empty() && !column.is_empty() => {
+            Ok((table.to_string(), column.to_string()))
+        }
+        _ => Err(format!("expected <table>=<column>, got {}", s)),
+    }
+}
+
+/// Parses a `--mutation-strategy` value of the form `<table>=<strategy>`
+pub fn parse_table_mutation_strategy(s: &str) -> Result<(String, MutationStrategy), String> {
+    let (table, strategy) = s
+        .split_once('=')
+        .ok_or_else(|| format!("expected <table>=<strategy>, got {}", s))?;
+    Ok((table.to_string(), strategy.parse()?))
+}
+
+/// Thresholds of an inserter, unset values falling back to the global settings
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct InserterSettings {
+    /// Timeout of each chunk sent to ClickHouse
+    pub send_timeout: Option<Duration>,
+    /// Timeout of the end of an insert
+    pub end_timeout: Option<Duration>,
+    /// Duration after which a commit ends the current insert
+    pub period: Option<Duration>,
+    /// Number of rows after which a commit ends the current insert
+    pub max_entries: Option<u64>,
+    /// Size after which a commit ends the current insert
+    pub max_bytes: Option<u64>,
+}
+
+impl InserterSettings {
+    /// Settings with the values set in `other` taking precedence
+    pub fn merge(&self, other: &InserterSettings) -> Self {
+        Self {
+            send_timeout: other.send_timeout.or(self.send_timeout),
+            end_timeout: other.end_timeout.or(self.end_timeout),
+            period: other.period.or(self.period),
+            max_entries: other.max_entries.or(self.max_entries),
+            max_bytes: other.max_bytes.or(self.max_bytes),
+        }
+    }
+}
+
+impl FromStr for InserterSettings {
+    type Err = String;
+
+    /// Parses a comma-separated list of `<setting>=<value>`, where setting is
+    /// `send-timeout`, `end-timeout` or `period` in seconds, `max-entries` or
+    /// `max-bytes`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut settings = InserterSettings::default();
+        for setting in s.split(',') {
+            let (name, value) = setting
+                .split_once('=')
+                .ok_or_else(|| format!("expected <setting>=<value>, got {}", setting))?;
+            let value = value
+                .parse::<u64>()
+                .map_err(|e| format!("invalid value for {}: {}", name, e))?;
+            match name {
+                "send-timeout" => settings.send_timeout = Some(Duration::from_secs(value)),
+                "end-timeout" => settings.end_timeout = Some(Duration::from_secs(value)),
+                "period" => settings.period = Some(Duration::from_secs(value)),
+                "max-entries" => settings.max_entries = Some(value),
+                "max-bytes" => settings.max_bytes = Some(value),
+                _ => return Err(format!("unknown inserter setting {}", name)),
+            }
+        }
+        Ok(settings)
+    }
+}
+
+/// Parses a `--table-inserter` value of the form `<table>=<setting>=<value>[,...]`
+pub fn parse_table_inserter_settings(s: &str) -> Result<(String, InserterSettings), String> {
+    let (table, settings) = s
+        .split_once('=')
+        .ok_or_else(|| format!("expected <table>=<settings>, got {}", s))?;
+    Ok((table.to_string(), settings.parse()?))
 }
 
 #[derive(Clone)]
 pub struct DynamicTable {
     pub table_name: String,
     column_info: Vec<ColumnInfo>,
+    options: ConversionOptions,
+    mutation_strategy: MutationStrategy,
+    key_column: Option<String>,
+    inserter_settings: InserterSettings,
 }
 impl DynamicTable {
     pub fn new(table_name: &str, column_info: Vec<ColumnInfo>) -> Self {
         Self {
             table_name: table_name.to_string(),
             column_info,
+            options: ConversionOptions::default(),
+            mutation_strategy: MutationStrategy::default(),
+            key_column: None,
+            inserter_settings: InserterSettings::default(),
         }
     }
+
+    pub fn with_options(mut self, options: ConversionOptions) -> Self {
+        self.options = options;
+        self
+    }
+
+    pub fn with_mutation_strategy(mut self, mutation_strategy: MutationStrategy) -> Self {
+        self.mutation_strategy = mutation_strategy;
+        self
+    }
+
+    pub fn mutation_strategy(&self) -> &MutationStrategy {
+        &self.mutation_strategy
+    }
+
+    /// Sets the column receiving the single-column primary key of the changes
+    pub fn with_key_column(mut self, key_column: Option<String>) -> Self {
+        self.key_column = key_column;
+        self
+    }
+
+    pub fn key_column(&self) -> Option<&str> {
+        self.key_column.as_deref()
+    }
+
+    /// Sets the inserter settings of the table, overriding the global ones
+    pub fn with_inserter_settings(mut self, inserter_settings: InserterSettings) -> Self {
+        self.inserter_settings = inserter_settings;
+        self
+    }
+
+    pub fn inserter_settings(&self) -> &InserterSettings {
+        &self.inserter_settings
+    }
+
+    pub fn columns(&self) -> &[ColumnInfo] {
+        &self.column_info
+    }
+
+    pub fn has_column(&self, column_name: &str) -> bool {
+        self.column_info
+            .iter()
+            .any(|column| column.column_name == column_name)
+    }
+
+    /// Renders a `WHERE` condition matching the row with the given primary key,
+    /// the key being converted like the fields of an insert
+    pub fn key_condition(
+        &self,
+        keys: &HashMap<String, String>,
+    ) -> Result<String, FieldConversionError> {
+        let mut keys = keys.iter().collect::<Vec<_>>();
+        keys.sort();
+        // unknown columns are left for ClickHouse to reject
+        let unknown = ColumnType::String;
+        let conditions = keys
+            .into_iter()
+            .map(|(column_name, value)| {
+                let data_type = self
+                    .column_info
+                    .iter()
+                    .find(|column| &column.column_name == column_name)
+                    .map_or(&unknown, |column| &column.data_type);
+                let hex = self.options.is_hex_column(column_name);
+                let value = data_type
+                    .convert(Some(value.as_str()), &self.options, hex)
+                    .map_err(|source| FieldConversionError {
+                        column: column_name.clone(),
+                        data_type: data_type.clone(),
+                        value: Some(value.clone()),
+                        source,
+                    })?;
+                Ok(format!(
+                    "{} = {}",
+                    quote_identifier(column_name),
+                    data_type.sql_literal(&value)
+                ))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(conditions.join(" AND "))
+    }
 }
 
 impl Schema for DynamicTable {
@@ -66,104 +386,693 @@ impl Schema for DynamicTable {
 }
 
 impl DynamicInsert {
-    pub fn new(table_info: DynamicTable, data: HashMap<String, String>) -> Self {
-        Self { table_info, data }
+    /// Converts the fields of a row, failing on the first field that doesn't fit
+    /// its column so that nothing is written for an invalid row
+    pub fn new(
+        table_info: &DynamicTable,
+        data: &HashMap<String, String>,
+    ) -> Result<Self, FieldConversionError> {
+        let values = table_info
+            .column_info
+            .iter()
+            .map(|column| {
+                let value = data.get(&column.column_name).map(String::as_str);
+                let hex = table_info.options.is_hex_column(&column.column_name);
+                column
+                    .data_type
+                    .convert(value, &table_info.options, hex)
+                    .map_err(|source| FieldConversionError {
+                        column: column.column_name.clone(),
+                        data_type: column.data_type.clone(),
+                        value: value.map(str::to_string),
+                        source,
+                    })
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(Self { values })
+    }
+
+    /// Like `new`, but columns absent from `data` get their default value, as
+    /// tombstone rows only carry the key of the row they cancel
+    pub fn with_defaults(
+        table_info: &DynamicTable,
+        data: &HashMap<String, String>,
+    ) -> Result<Self, FieldConversionError> {
+        let values = table_info
+            .column_info
+            .iter()
+            .map(|column| {
+                let value = data.get(&column.column_name).map(String::as_str);
+                let hex = table_info.options.is_hex_column(&column.column_name);
+                let result = match value {
+                    Some(value) => column
+                        .data_type
+                        .convert(Some(value), &table_info.options, hex),
+                    None => column.data_type.default_value(&table_info.options),
+                };
+                result.map_err(|source| FieldConversionError {
+                    column: column.column_name.clone(),
+                    data_type: column.data_type.clone(),
+                    value: value.map(str::to_string),
+                    source,
+                })
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(Self { values })
     }
 }
+
 impl Serialize for DynamicInsert {
     fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
     where
         S: serde::Serializer,
     {
-        let mut serializer = serializer.serialize_tuple(self.table_info.column_info.len())?;
-        for column in self.table_info.column_info.iter() {
-            let data = self.data.get(&column.column_name);
-            if let Some(data) = data {
-                match column.data_type {
-                    ColumnType::String => serializer.serialize_element(data)?,
-                    ColumnType::Float32 => {
-                        let value = &data.parse::<f32>().unwrap();
-                        serializer.serialize_element(value)?;
-                    }
-                    ColumnType::Float64 => {
-                        let value = &data.parse::<f64>().unwrap();
-                        serializer.serialize_element(value)?;
-                    }
-                    ColumnType::UInt8 => {
-                        let value = &data.parse::<u8>().unwrap();
-                        serializer.serialize_element(value)?;
-                    }
-                    ColumnType::UInt16 => {
-                        let value = &data.parse::<u16>().unwrap();
-                        serializer.serialize_element(value)?;
-                    }
-                    ColumnType::UInt32 => {
-                        let value = &data.parse::<u32>().unwrap();
-                        serializer.serialize_element(value)?;
-                    }
-                    ColumnType::UInt64 => {
-                        let value = &data.parse::<u64>().unwrap();
-                        serializer.serialize_element(value)?;
-                    }
-                    ColumnType::UInt128 => {
-                        let value = &data.parse::<u128>().unwrap();
-                        serializer.serialize_element(value)?;
-                    }
-                    ColumnType::UInt256 => {
-                        let value = U256::from_dec_str(data).unwrap().0;
-                        serializer.serialize_element(&value)?;
-                    }
-                    ColumnType::Int8 => {
-                        let value = &data.parse::<i8>().unwrap();
-                        serializer.serialize_element(value)?;
-                    }
-                    ColumnType::Int16 => {
-                        let value = &data.parse::<i16>().unwrap();
-                        serializer.serialize_element(value)?;
-                    }
-                    ColumnType::Int32 => {
-                        let value = &data.parse::<i32>().unwrap();
-                        serializer.serialize_element(value)?;
-                    }
-                    ColumnType::Int64 => {
-                        let value = &data.parse::<i64>().unwrap();
-                        serializer.serialize_element(value)?;
-                    }
-                    ColumnType::Int128 => {
-                        let value = &data.parse::<i128>().unwrap();
-                        serializer.serialize_element(value)?;
-                    }
-                    ColumnType::Int256 => {
-                        let value = U256::from_dec_str(data).unwrap().0;
-                        serializer.serialize_element(&value)?;
-                    }
-                    ColumnType::FixedString(size) => {
-                        let bytes = data.as_bytes();
-                        for i in 0..size {
-                            let v = if i < bytes.len() { bytes[i] } else { 0 };
-                            serializer.serialize_element(&v)?;
-                        }
-                    }
-                    ColumnType::Bool => {
-                        let value = &data.parse::<bool>().unwrap();
-                        serializer.serialize_element(value)?;
-                    }
-                    ColumnType::DateTime => {
-                        let time = chrono::DateTime::parse_from_rfc3339(data)
-                            .unwrap()
-                            .timestamp() as i32;
-                        serializer.serialize_element(&time)?;
-                    }
-                    ColumnType::Date
-                    | ColumnType::Nullable(_)
-                    | ColumnType::LowCardinality
-                    | ColumnType::Decimal => {
-                        unimplemented!("{:?} not implemented", column.data_type)
-                    }
+        let mut serializer = serializer.serialize_tuple(self.values.len())?;
+        for value in self.values.iter() {
+            serializer.serialize_element(value)?;
+        }
+        serializer.end()
+    }
+}
+
+/// Values that are written as NULL in a Nullable column
+const NULL_VALUES: [&str; 3] = ["", "null", "NULL"];
+
+/// Days since the unix epoch supported by `Date32`, from 1900-01-01 to 2299-12-31
+const DATE32_RANGE: std::ops::RangeInclusive<i64> = -25567..=120529;
+
+/// A field value converted to its column type, serialized as RowBinary
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Null,
+    NotNull(Box<Value>),
+    Bool(bool),
+    UInt8(u8),
+    UInt16(u16),
+    UInt32(u32),
+    UInt64(u64),
+    UInt128(u128),
+    Int8(i8),
+    Int16(i16),
+    Int32(i32),
+    Int64(i64),
+    Int128(i128),
+    Float32(f32),
+    Float64(f64),
+    /// 256 bits integers, as little endian limbs
+    Int256([u64; 4]),
+    String(String),
+    /// A `String` holding raw bytes
+    Bytes(Vec<u8>),
+    /// Fixed size values like `FixedString(N)` and `IPv6`
+    FixedBytes(Vec<u8>),
+    Array(Vec<Value>),
+    Tuple(Vec<Value>),
+}
+
+impl Serialize for Value {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        match self {
+            Value::Null => serializer.serialize_none(),
+            Value::NotNull(value) => serializer.serialize_some(value),
+            Value::Bool(value) => serializer.serialize_bool(*value),
+            Value::UInt8(value) => serializer.serialize_u8(*value),
+            Value::UInt16(value) => serializer.serialize_u16(*value),
+            Value::UInt32(value) => serializer.serialize_u32(*value),
+            Value::UInt64(value) => serializer.serialize_u64(*value),
+            Value::UInt128(value) => serializer.serialize_u128(*value),
+            Value::Int8(value) => serializer.serialize_i8(*value),
+            Value::Int16(value) => serializer.serialize_i16(*value),
+            Value::Int32(value) => serializer.serialize_i32(*value),
+            Value::Int64(value) => serializer.serialize_i64(*value),
+            Value::Int128(value) => serializer.serialize_i128(*value),
+            Value::Float32(value) => serializer.serialize_f32(*value),
+            Value::Float64(value) => serializer.serialize_f64(*value),
+            Value::Int256(limbs) => limbs.serialize(serializer),
+            Value::String(value) => serializer.serialize_str(value),
+            Value::Bytes(value) => serializer.serialize_bytes(value),
+            Value::FixedBytes(bytes) => {
+                let mut serializer = serializer.serialize_tuple(bytes.len())?;
+                for byte in bytes {
+                    serializer.serialize_element(byte)?;
+                }
+                serializer.end()
+            }
+            Value::Array(values) => {
+                let mut serializer = serializer.serialize_seq(Some(values.len()))?;
+                for value in values {
+                    serializer.serialize_element(value)?;
+                }
+                serializer.end()
+            }
+            Value::Tuple(values) => {
+                let mut serializer = serializer.serialize_tuple(values.len())?;
+                for value in values {
+                    serializer.serialize_element(value)?;
                 }
+                serializer.end()
             }
         }
-        serializer.end()
+    }
+}
+
+impl ColumnType {
+    /// Converts a field into a value of this type. `value` is `None` when the field
+    /// is absent, which is only accepted by nullable types.
+    pub fn convert(
+        &self,
+        value: Option<&str>,
+        options: &ConversionOptions,
+        hex: bool,
+    ) -> Result<Value, ConversionError> {
+        let data = match (self, value) {
+            (ColumnType::Nullable(inner), Some(value)) if !NULL_VALUES.contains(&value) => {
+                let value = inner.convert(Some(value), options, hex)?;
+                return Ok(Value::NotNull(Box::new(value)));
+            }
+            (ColumnType::Nullable(_), _) => return Ok(Value::Null),
+            (ColumnType::LowCardinality(inner), value) => {
+                return inner.convert(value, options, hex);
+            }
+            (_, Some(data)) => data,
+            (_, None) => return Err(ConversionError::MissingValue),
+        };
+
+        let value = match self {
+            ColumnType::Nullable(_) | ColumnType::LowCardinality(_) => unreachable!(),
+            ColumnType::Array(inner) => {
+                let delimiter = options.array_delimiter.as_deref();
+                let values = parse_array(data, delimiter)?
+                    .iter()
+                    .map(|element| inner.convert(element.as_deref(), options, hex))
+                    .collect::<Result<_, _>>()?;
+                Value::Array(values)
+            }
+            ColumnType::Map(key_type, value_type) => {
+                // maps are written as an array of (key, value) tuples
+                let entries = parse_map(data)?
+                    .iter()
+                    .map(|(key, value)| {
+                        Ok(Value::Tuple(vec![
+                            key_type.convert(Some(key), options, hex)?,
+                            value_type.convert(value.as_deref(), options, hex)?,
+                        ]))
+                    })
+                    .collect::<Result<_, _>>()?;
+                Value::Array(entries)
+            }
+            ColumnType::Tuple(elements) => {
+                let names = elements
+                    .iter()
+                    .map(|(name, _)| name.as_deref())
+                    .collect::<Vec<_>>();
+                let values = parse_tuple(data, &names)?
+                    .iter()
+                    .zip(elements.iter())
+                    .map(|(value, (_, element_type))| {
+                        element_type.convert(value.as_deref(), options, hex)
+                    })
+                    .collect::<Result<_, _>>()?;
+                Value::Tuple(values)
+            }
+            ColumnType::Uuid => {
+                let (high, low) = parse_uuid(data)?;
+                Value::Tuple(vec![Value::UInt64(high), Value::UInt64(low)])
+            }
+            ColumnType::IPv4 => Value::UInt32(u32::from(parse_ipv4(data)?)),
+            ColumnType::IPv6 => Value::FixedBytes(parse_ipv6(data)?.octets().to_vec()),
+            ColumnType::Enum8(variants) => Value::Int8(parse_enum(data, variants)?),
+            ColumnType::Enum16(variants) => Value::Int16(parse_enum(data, variants)?),
+            ColumnType::Unsupported(data_type) => {
+                return Err(ConversionError::UnsupportedType(data_type.clone()))
+            }
+            ColumnType::String if hex => Value::Bytes(decode_hex(data)?),
+            ColumnType::String => Value::String(data.to_string()),
+            ColumnType::Float32 => Value::Float32(parse_float(data)?),
+            ColumnType::Float64 => Value::Float64(parse_float(data)?),
+            ColumnType::UInt8 => Value::UInt8(parse_integer(data, "UInt8")?),
+            ColumnType::UInt16 => Value::UInt16(parse_integer(data, "UInt16")?),
+            ColumnType::UInt32 => Value::UInt32(parse_integer(data, "UInt32")?),
+            ColumnType::UInt64 => Value::UInt64(parse_integer(data, "UInt64")?),
+            ColumnType::UInt128 => Value::UInt128(parse_integer(data, "UInt128")?),
+            ColumnType::UInt256 => Value::Int256(parse_uint256(data)?.0),
+            ColumnType::Int8 => Value::Int8(parse_integer(data, "Int8")?),
+            ColumnType::Int16 => Value::Int16(parse_integer(data, "Int16")?),
+            ColumnType::Int32 => Value::Int32(parse_integer(data, "Int32")?),
+            ColumnType::Int64 => Value::Int64(parse_integer(data, "Int64")?),
+            ColumnType::Int128 => Value::Int128(parse_integer(data, "Int128")?),
+            ColumnType::Int256 => Value::Int256(parse_int256(data)?.0),
+            ColumnType::FixedString(size) => {
+                let bytes = if hex {
+                    decode_hex(data)?
+                } else {
+                    data.as_bytes().to_vec()
+                };
+                Value::FixedBytes(fixed_string_bytes(data, bytes, *size)?)
+            }
+            ColumnType::Bool => Value::Bool(parse_bool(data)?),
+            ColumnType::DateTime(timezone) => {
+                let time = u32::try_from(parse_datetime(data, timezone.as_deref())?.timestamp())
+                    .map_err(|_| ConversionError::DateTimeOutOfRange(data.into(), "DateTime"))?;
+                Value::UInt32(time)
+            }
+            ColumnType::DateTime64(precision, timezone) => {
+                Value::Int64(parse_datetime64(data, *precision, timezone.as_deref())?)
+            }
+            ColumnType::Date => {
+                let days = u16::try_from(parse_days(data)?)
+                    .map_err(|_| ConversionError::DateTimeOutOfRange(data.into(), "Date"))?;
+                Value::UInt16(days)
+            }
+            ColumnType::Date32 => {
+                let days = parse_days(data)?;
+                if !DATE32_RANGE.contains(&days) {
+                    return Err(ConversionError::DateTimeOutOfRange(data.into(), "Date32"));
+                }
+                Value::Int32(days as i32)
+            }
+            ColumnType::Decimal(precision, scale) => {
+                let value = parse_decimal(data, *precision, *scale)?;
+                match precision {
+                    0..=9 => Value::Int32(value.low_u32() as i32),
+                    10..=18 => Value::Int64(value.low_u64() as i64),
+                    19..=38 => Value::Int128(value.low_u128() as i128),
+                    _ => Value::Int256(value.0),
+                }
+            }
+        };
+        Ok(value)
+    }
+
+    /// The ClickHouse default of the type, written for the columns missing from
+    /// a tombstone row
+    pub fn default_value(&self, options: &ConversionOptions) -> Result<Value, ConversionError> {
+        let data = match self {
+            ColumnType::Nullable(_) => return Ok(Value::Null),
+            ColumnType::LowCardinality(inner) => return inner.default_value(options),
+            ColumnType::Tuple(elements) => {
+                let values = elements
+                    .iter()
+                    .map(|(_, element_type)| element_type.default_value(options))
+                    .collect::<Result<_, _>>()?;
+                return Ok(Value::Tuple(values));
+            }
+            ColumnType::String | ColumnType::FixedString(_) => "",
+            ColumnType::Bool => "false",
+            ColumnType::Array(_) => "[]",
+            ColumnType::Map(..) => "{}",
+            ColumnType::Uuid => "00000000-0000-0000-0000-000000000000",
+            ColumnType::IPv4 => "0.0.0.0",
+            ColumnType::IPv6 => "::",
+            ColumnType::Enum8(variants) => variants.first().map_or("", |(name, _)| name),
+            ColumnType::Enum16(variants) => variants.first().map_or("", |(name, _)| name),
+            _ => "0",
+        };
+        self.convert(Some(data), options, false)
+    }
+
+    /// Renders a value converted to this type as a SQL literal, for the queries
+    /// that can't send it as RowBinary like the `WHERE` clause of a delete.
+    /// Strings and bytes are written as `unhex(...)`.
+    pub fn sql_literal(&self, value: &Value) -> String {
+        let quote = |value: &str| format!("'{}'", value.replace('\\', "\\\\").replace('\'', "\\'"));
+        let unhex = |bytes: &[u8]| format!("unhex('{}')", hex::encode(bytes));
+        match (self, value) {
+            (_, Value::Null) => "NULL".to_string(),
+            (ColumnType::Nullable(inner), Value::NotNull(value)) => inner.sql_literal(value),
+            (ColumnType::Nullable(inner) | ColumnType::LowCardinality(inner), value) => {
+                inner.sql_literal(value)
+            }
+            (ColumnType::Array(inner), Value::Array(values)) => {
+                let values = values
+                    .iter()
+                    .map(|value| inner.sql_literal(value))
+                    .collect::<Vec<_>>();
+                format!("[{}]", values.join(", "))
+            }
+            (ColumnType::Map(key_type, value_type), Value::Array(entries)) => {
+                let entries = entries
+                    .iter()
+                    .flat_map(|entry| match entry {
+                        Value::Tuple(pair) if pair.len() == 2 => vec![
+                            key_type.sql_literal(&pair[0]),
+                            value_type.sql_literal(&pair[1]),
+                        ],
+                        _ => vec![],
+                    })
+                    .collect::<Vec<_>>();
+                format!("map({})", entries.join(", "))
+            }
+            (ColumnType::Tuple(elements), Value::Tuple(values)) => {
+                let values = elements
+                    .iter()
+                    .zip(values)
+                    .map(|((_, element_type), value)| element_type.sql_literal(value))
+                    .collect::<Vec<_>>();
+                format!("tuple({})", values.join(", "))
+            }
+            (ColumnType::Uuid, Value::Tuple(values)) => match values.as_slice() {
+                [Value::UInt64(high), Value::UInt64(low)] => {
+                    let uuid = format!("{:016x}{:016x}", high, low);
+                    quote(&format!(
+                        "{}-{}-{}-{}-{}",
+                        &uuid[..8],
+                        &uuid[8..12],
+                        &uuid[12..16],
+                        &uuid[16..20],
+                        &uuid[20..]
+                    ))
+                }
+                _ => "NULL".to_string(),
+            },
+            (ColumnType::IPv4, Value::UInt32(ip)) => quote(&Ipv4Addr::from(*ip).to_string()),
+            (ColumnType::IPv6, Value::FixedBytes(bytes)) => {
+                match <[u8; 16]>::try_from(&bytes[..]) {
+                    Ok(octets) => quote(&Ipv6Addr::from(octets).to_string()),
+                    Err(_) => unhex(bytes),
+                }
+            }
+            (ColumnType::Enum8(variants), Value::Int8(number)) => variants
+                .iter()
+                .find(|(_, value)| value == number)
+                .map_or_else(|| number.to_string(), |(name, _)| quote(name)),
+            (ColumnType::Enum16(variants), Value::Int16(number)) => variants
+                .iter()
+                .find(|(_, value)| value == number)
+                .map_or_else(|| number.to_string(), |(name, _)| quote(name)),
+            (ColumnType::DateTime(_), Value::UInt32(seconds)) => {
+                format!("toDateTime({}, 'UTC')", seconds)
+            }
+            (ColumnType::DateTime64(precision, _), Value::Int64(ticks)) => {
+                format!(
+                    "fromUnixTimestamp64Nano({}, 'UTC')",
+                    scale_ticks(*ticks, *precision)
+                )
+            }
+            (ColumnType::Date, Value::UInt16(days)) => {
+                format!("addDays(toDate('1970-01-01'), {})", days)
+            }
+            (ColumnType::Date32, Value::Int32(days)) => {
+                format!("addDays(toDate32('1970-01-01'), {})", days)
+            }
+            (ColumnType::Decimal(_, scale), value) => {
+                let digits = match value {
+                    Value::Int32(value) => value.to_string(),
+                    Value::Int64(value) => value.to_string(),
+                    Value::Int128(value) => value.to_string(),
+                    Value::Int256(limbs) => signed_int256(limbs),
+                    _ => return "NULL".to_string(),
+                };
+                format!(
+                    "toDecimal256({}, {})",
+                    quote(&insert_decimal_point(&digits, *scale)),
+                    scale
+                )
+            }
+            (ColumnType::Int256, Value::Int256(limbs)) => signed_int256(limbs),
+            (_, Value::Int256(limbs)) => U256(*limbs).to_string(),
+            (_, Value::Bool(value)) => value.to_string(),
+            (_, Value::UInt8(value)) => value.to_string(),
+            (_, Value::UInt16(value)) => value.to_string(),
+            (_, Value::UInt32(value)) => value.to_string(),
+            (_, Value::UInt64(value)) => value.to_string(),
+            (_, Value::UInt128(value)) => value.to_string(),
+            (_, Value::Int8(value)) => value.to_string(),
+            (_, Value::Int16(value)) => value.to_string(),
+            (_, Value::Int32(value)) => value.to_string(),
+            (_, Value::Int64(value)) => value.to_string(),
+            (_, Value::Int128(value)) => value.to_string(),
+            (_, Value::Float32(value)) => value.to_string(),
+            (_, Value::Float64(value)) => value.to_string(),
+            // strings are hex encoded too, a `?` in them would be read as a bind
+            (_, Value::String(value)) => unhex(value.as_bytes()),
+            (_, Value::Bytes(bytes) | Value::FixedBytes(bytes)) => unhex(bytes),
+            (_, Value::NotNull(value)) => self.sql_literal(value),
+            (_, Value::Array(_) | Value::Tuple(_)) => "NULL".to_string(),
+        }
+    }
+
+    /// Returns true if the column accepts NULL, looking through `LowCardinality`
+    pub fn is_nullable(&self) -> bool {
+        match self {
+            ColumnType::Nullable(_) => true,
+            ColumnType::LowCardinality(inner) => inner.is_nullable(),
+            _ => false,
+        }
+    }
+
+    /// Parses a ClickHouse type name as returned by `information_schema.columns`,
+    /// e.g. `Nullable(FixedString(40))`
+    pub fn from_type_name(data_type: &str) -> Result<Self, ElricError> {
+        let unsupported = || ElricError::UnsupportedColumnType(data_type.to_string());
+        let (name, args) = split_type_name(data_type);
+
+        if let Some(precision) = decimal_precision(name) {
+            let args = args.map(split_type_args).unwrap_or_default();
+            let parse = |arg: &str| arg.parse::<u32>().map_err(|_| unsupported());
+            let (precision, scale) = match (precision, args.as_slice()) {
+                (Some(precision), [scale]) => (precision, parse(scale)?),
+                (None, [precision]) => (parse(precision)?, 0),
+                (None, [precision, scale]) => (parse(precision)?, parse(scale)?),
+                _ => return Err(unsupported()),
+            };
+            if !(1..=76).contains(&precision) || scale > precision {
+                return Err(unsupported());
+            }
+            return Ok(ColumnType::Decimal(precision, scale));
+        }
+
+        let column_type = ColumnType::from_str(name).map_err(|_| unsupported())?;
+
+        match (column_type, args) {
+            (ColumnType::FixedString(_), Some(args)) => {
+                let size = args.trim().parse().map_err(|_| unsupported())?;
+                Ok(ColumnType::FixedString(size))
+            }
+            (ColumnType::DateTime(_), Some(args)) => Ok(ColumnType::DateTime(Some(unquote(args)))),
+            (ColumnType::DateTime64(..), Some(args)) => match split_type_args(args).as_slice() {
+                [precision] => Ok(ColumnType::DateTime64(
+                    precision.parse().map_err(|_| unsupported())?,
+                    None,
+                )),
+                [precision, timezone] => Ok(ColumnType::DateTime64(
+                    precision.parse().map_err(|_| unsupported())?,
+                    Some(unquote(timezone)),
+                )),
+                _ => Err(unsupported()),
+            },
+            (ColumnType::Nullable(_), Some(args)) => {
+                let inner = ColumnType::from_type_name(args)?;
+                Ok(ColumnType::Nullable(Box::new(inner)))
+            }
+            (ColumnType::LowCardinality(_), Some(args)) => {
+                let inner = ColumnType::from_type_name(args)?;
+                Ok(ColumnType::LowCardinality(Box::new(inner)))
+            }
+            (ColumnType::Array(_), Some(args)) => {
+                let inner = ColumnType::from_type_name(args)?;
+                Ok(ColumnType::Array(Box::new(inner)))
+            }
+            (ColumnType::Map(..), Some(args)) => match split_type_args(args).as_slice() {
+                [key_type, value_type] => Ok(ColumnType::Map(
+                    Box::new(ColumnType::from_type_name(key_type)?),
+                    Box::new(ColumnType::from_type_name(value_type)?),
+                )),
+                _ => Err(unsupported()),
+            },
+            (ColumnType::Enum8(_), Some(args)) => Ok(ColumnType::Enum8(parse_enum_variants(args)?)),
+            (ColumnType::Enum16(_), Some(args)) => {
+                Ok(ColumnType::Enum16(parse_enum_variants(args)?))
+            }
+            (ColumnType::Tuple(_), Some(args)) => {
+                let elements = split_type_args(args)
+                    .into_iter()
+                    .map(parse_tuple_element)
+                    .collect::<Result<Vec<_>, _>>()?;
+                Ok(ColumnType::Tuple(elements))
+            }
+            (
+                ColumnType::FixedString(_)
+                | ColumnType::DateTime64(..)
+                | ColumnType::Nullable(_)
+                | ColumnType::LowCardinality(_)
+                | ColumnType::Array(_)
+                | ColumnType::Map(..)
+                | ColumnType::Tuple(_)
+                | ColumnType::Enum8(_)
+                | ColumnType::Enum16(_),
+                None,
+            ) => Err(unsupported()),
+            (column_type, _) => Ok(column_type),
+        }
+    }
+}
+
+/// Parses the variants of an enum type, e.g. `'buy' = 1, 'sell' = -1`
+fn parse_enum_variants<T: FromStr>(args: &str) -> Result<Vec<(String, T)>, ElricError> {
+    split_type_args(args)
+        .into_iter()
+        .map(|variant| {
+            let (name, value) = variant.rsplit_once('=')?;
+            Some((unquote(name), value.trim().parse().ok()?))
+        })
+        .collect::<Option<Vec<_>>>()
+        .ok_or_else(|| ElricError::UnsupportedColumnType(args.to_string()))
+}
+
+/// Parses a tuple element, either a bare type (`UInt64`) or a named one (`amount UInt64`)
+fn parse_tuple_element(element: &str) -> Result<(Option<String>, ColumnType), ElricError> {
+    match ColumnType::from_type_name(element) {
+        Ok(element_type) => Ok((None, element_type)),
+        Err(error) => {
+            let (name, element_type) = element.split_once(char::is_whitespace).ok_or(error)?;
+            let name = name.trim_matches('`').to_string();
+            Ok((Some(name), ColumnType::from_type_name(element_type)?))
+        }
+    }
+}
+
+/// Returns the implicit precision for the `Decimal` family of type names,
+/// `Some(None)` meaning that the precision is given as an argument
+fn decimal_precision(name: &str) -> Option<Option<u32>> {
+    match name {
+        "Decimal" => Some(None),
+        "Decimal32" => Some(Some(9)),
+        "Decimal64" => Some(Some(18)),
+        "Decimal128" => Some(Some(38)),
+        "Decimal256" => Some(Some(76)),
+        _ => None,
+    }
+}
+
+/// Splits the arguments of a type on top level commas,
+/// e.g. `String, Nullable(Decimal(9, 2))` into `["String", "Nullable(Decimal(9, 2))"]`
+fn split_type_args(args: &str) -> Vec<&str> {
+    let mut result = vec![];
+    let mut depth = 0;
+    let mut quoted = false;
+    let mut escaped = false;
+    let mut start = 0;
+    for (index, c) in args.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if quoted => escaped = true,
+            '\'' => quoted = !quoted,
+            '(' if !quoted => depth += 1,
+            ')' if !quoted => depth -= 1,
+            ',' if !quoted && depth == 0 => {
+                result.push(args[start..index].trim());
+                start = index + 1;
+            }
+            _ => {}
+        }
+    }
+    result.push(args[start..].trim());
+    result
+}
+
+/// Text of a two's complement 256 bits integer
+fn signed_int256(limbs: &[u64; 4]) -> String {
+    let value = U256(*limbs);
+    if value.bit(255) {
+        format!("-{}", (!value).overflowing_add(U256::one()).0)
+    } else {
+        value.to_string()
+    }
+}
+
+/// Places the decimal point of the unscaled digits of a decimal, e.g. `-105`
+/// with a scale of 2 is `-1.05`
+fn insert_decimal_point(digits: &str, scale: u32) -> String {
+    let (sign, digits) = match digits.strip_prefix('-') {
+        Some(digits) => ("-", digits),
+        None => ("", digits),
+    };
+    let scale = scale as usize;
+    if scale == 0 {
+        return format!("{}{}", sign, digits);
+    }
+    let digits = format!("{:0>width$}", digits, width = scale + 1);
+    let (integer, fraction) = digits.split_at(digits.len() - scale);
+    format!("{}{}.{}", sign, integer, fraction)
+}
+
+/// Nanoseconds since the unix epoch of `DateTime64` ticks
+fn scale_ticks(ticks: i64, precision: u32) -> i128 {
+    i128::from(ticks) * 10_i128.pow(9 - precision.min(9))
+}
+
+/// Removes the quotes of a string literal in a type, e.g. `'UTC'`
+fn unquote(value: &str) -> String {
+    let value = value.trim();
+    value
+        .strip_prefix('\'')
+        .and_then(|value| value.strip_suffix('\''))
+        .unwrap_or(value)
+        .replace("\\'", "'")
+}
+
+/// Splits a type name like `Nullable(UInt64)` into `("Nullable", Some("UInt64"))`
+fn split_type_name(data_type: &str) -> (&str, Option<&str>) {
+    let data_type = data_type.trim();
+    match data_type.find('(') {
+        Some(index) if data_type.ends_with(')') => (
+            &data_type[..index],
+            Some(&data_type[index + 1..data_type.len() - 1]),
+        ),
+        _ => (data_type, None),
+    }
+}
+
+/// Formats the ClickHouse name of the type, e.g. `Nullable(FixedString(40))`
+impl Display for ColumnType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let quote = |value: &str| format!("'{}'", value.replace('\'', "\\'"));
+        match self {
+            ColumnType::FixedString(size) => write!(f, "FixedString({})", size),
+            ColumnType::DateTime(None) => write!(f, "DateTime"),
+            ColumnType::DateTime(Some(timezone)) => write!(f, "DateTime({})", quote(timezone)),
+            ColumnType::DateTime64(precision, None) => write!(f, "DateTime64({})", precision),
+            ColumnType::DateTime64(precision, Some(timezone)) => {
+                write!(f, "DateTime64({}, {})", precision, quote(timezone))
+            }
+            ColumnType::LowCardinality(inner) => write!(f, "LowCardinality({})", inner),
+            ColumnType::Decimal(precision, scale) => write!(f, "Decimal({}, {})", precision, scale),
+            ColumnType::Nullable(inner) => write!(f, "Nullable({})", inner),
+            ColumnType::Array(inner) => write!(f, "Array({})", inner),
+            ColumnType::Map(key_type, value_type) => write!(f, "Map({}, {})", key_type, value_type),
+            ColumnType::Tuple(elements) => {
+                let elements = elements
+                    .iter()
+                    .map(|(name, element_type)| match name {
+                        Some(name) => format!("{} {}", name, element_type),
+                        None => element_type.to_string(),
+                    })
+                    .collect::<Vec<_>>();
+                write!(f, "Tuple({})", elements.join(", "))
+            }
+            ColumnType::Uuid => write!(f, "UUID"),
+            ColumnType::Enum8(variants) => {
+                let variants = variants
+                    .iter()
+                    .map(|(name, value)| format!("{} = {}", quote(name), value))
+                    .collect::<Vec<_>>();
+                write!(f, "Enum8({})", variants.join(", "))
+            }
+            ColumnType::Enum16(variants) => {
+                let variants = variants
+                    .iter()
+                    .map(|(name, value)| format!("{} = {}", quote(name), value))
+                    .collect::<Vec<_>>();
+                write!(f, "Enum16({})", variants.join(", "))
+            }
+            ColumnType::Unsupported(data_type) => write!(f, "{}", data_type),
+            // the remaining types have no arguments
+            data_type => write!(f, "{:?}", data_type),
+        }
     }
 }
 
@@ -173,25 +1082,8 @@ impl<'de> Deserialize<'de> for ColumnType {
         D: serde::Deserializer<'de>,
     {
         let data_type: String = Deserialize::deserialize(deserializer)?;
-        let data = data_type
-            .split('(')
-            .collect::<VecDeque<_>>()
-            .pop_front()
-            .unwrap();
-
-        let column_type = ColumnType::from_str(data)
-            .unwrap_or_else(|_| panic!("unimplemented column type {}", data_type));
-        match column_type {
-            ColumnType::FixedString(_) => {
-                let size: usize = data_type
-                    .replace("FixedString(", "")
-                    .replace(')', "")
-                    .parse()
-                    .expect("could not get fixedstring usize");
-                Ok(ColumnType::FixedString(size))
-            }
-            _ => Ok(column_type),
-        }
+        Ok(ColumnType::from_type_name(&data_type)
+            .unwrap_or_else(|_| ColumnType::Unsupported(data_type)))
     }
 }
 
@@ -211,6 +1103,62 @@ impl Ord for ColumnInfo {
 pub struct TableInfo {
     pub table_schema: String,
     pub table_name: String,
+    /// Primary key expression of the table, e.g. `id` or `chain, id`
+    pub primary_key: String,
+}
+
+impl TableInfo {
+    /// The column of a primary key made of a single column
+    pub fn key_column(&self) -> Option<String> {
+        let key = self.primary_key.trim();
+        let is_column = !key.is_empty() && !key.contains([',', '(', ' ']);
+        is_column.then(|| key.trim_matches('`').to_string())
+    }
+}
+
+/// Settings of the tables given on the command line, by table name
+#[derive(Clone, Default)]
+pub struct TableSettings {
+    pub options: ConversionOptions,
+    pub hex_decode: Vec<String>,
+    pub mutation_strategies: HashMap<String, MutationStrategy>,
+    pub key_columns: HashMap<String, String>,
+    pub inserter_settings: HashMap<String, InserterSettings>,
+}
+
+impl TableSettings {
+    /// Builds the `DynamicTable` of a table with its settings
+    pub fn dynamic_table(&self, table: &TableInfo, columns: Vec<ColumnInfo>) -> DynamicTable {
+        let options = self
+            .options
+            .clone()
+            .with_hex_decode(&table.table_name, &self.hex_decode);
+        let mutation_strategy = self
+            .mutation_strategies
+            .get(&table.table_name)
+            .cloned()
+            .unwrap_or_default();
+        let key_column = self
+            .key_columns
+            .get(&table.table_name)
+            .cloned()
+            .or_else(|| table.key_column());
+        let inserter_settings = self
+            .inserter_settings
+            .get(&table.table_name)
+            .cloned()
+            .unwrap_or_default();
+        DynamicTable::new(&table.table_name, columns)
+            .with_options(options)
+            .with_mutation_strategy(mutation_strategy)
+            .with_key_column(key_column)
+            .with_inserter_settings(inserter_settings)
+    }
+}
+
+/// Quotes an identifier with backticks so that it can be used in a query
+pub fn quote_identifier(identifier: &str) -> String {
+    format!("`{}`", identifier.replace('\\', "\\\\").replace('`', "\\`"))
 }
 
 pub async fn get_columns(
@@ -218,50 +1166,503 @@ pub async fn get_columns(
     database: &str,
     table: &str,
 ) -> Result<Vec<ColumnInfo>, ElricError> {
-    let query = client.query(&format!(
-        "
+    let query = client
+        .query(
+            "
 	SELECT
 		column_name,
 		data_type
 	FROM
 		information_schema.columns
 	WHERE
-		table_schema = '{}' AND
-		table_name = '{}'
+		table_schema = ? AND
+		table_name = ?
 	ORDER BY
 		column_name,
 		data_type
                  ",
-        database, table
-    ));
-    let result = query
+        )
+        .bind(database)
+        .bind(table);
+    let result: Vec<ColumnInfo> = query
         .fetch_all()
         .await
         .map_err(|_| ElricError::ColumnNotFound(database.into(), table.into()))?;
+    for column in result.iter() {
+        if let ColumnType::Unsupported(data_type) = &column.data_type {
+            warn!(
+                table,
+                column = column.column_name,
+                data_type,
+                "Unsupported column type, rows with a value for it will fail"
+            );
+        }
+    }
     Ok(result)
 }
 
 pub async fn get_table_information(client: &Client) -> Result<Vec<TableInfo>, ElricError> {
-    let query = client.query(
-        format!(
+    let query = client
+        .query(
             "
             SELECT database AS table_schema,
-                     name AS table_name
+                     name AS table_name,
+                     primary_key
               FROM system.tables
               WHERE NOT is_temporary
                 AND engine NOT LIKE '%View'
                 AND engine NOT LIKE 'System%'
                 AND has_own_data != 0
-                AND database = '{}'
+                AND database = ?
               ORDER BY database, name
                  ",
-            client.database().unwrap_or("default")
         )
-        .as_str(),
-    );
+        .bind(client.database().unwrap_or("default"));
     let result = query
         .fetch_all()
         .await
-        .map_err(|e| ElricError::LoadSchemaError(e))?;
+        .map_err(ElricError::LoadSchemaError)?;
     Ok(result)
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, time::Duration};
+
+    use serde::{
+        de::{value::StrDeserializer, IntoDeserializer},
+        Deserialize,
+    };
+
+    use clickhouse::{test, Client};
+
+    use super::{
+        get_columns, parse_table_inserter_settings, parse_table_key_column,
+        parse_table_mutation_strategy, quote_identifier, ColumnInfo, ColumnType, ConversionOptions,
+        DynamicTable, InserterSettings, MutationStrategy, TableInfo, Value,
+    };
+
+    #[test]
+    fn test_parse_column_type() {
+        assert_eq!(
+            ColumnType::from_type_name("UInt64").unwrap(),
+            ColumnType::UInt64
+        );
+        assert_eq!(
+            ColumnType::from_type_name("FixedString(40)").unwrap(),
+            ColumnType::FixedString(40)
+        );
+        assert_eq!(
+            ColumnType::from_type_name("Nullable(UInt64)").unwrap(),
+            ColumnType::Nullable(Box::new(ColumnType::UInt64))
+        );
+        assert_eq!(
+            ColumnType::from_type_name("Nullable(FixedString(40))").unwrap(),
+            ColumnType::Nullable(Box::new(ColumnType::FixedString(40)))
+        );
+        assert_eq!(
+            ColumnType::from_type_name("LowCardinality(String)").unwrap(),
+            ColumnType::LowCardinality(Box::new(ColumnType::String))
+        );
+        assert_eq!(
+            ColumnType::from_type_name("LowCardinality(Nullable(String))").unwrap(),
+            ColumnType::LowCardinality(Box::new(ColumnType::Nullable(Box::new(
+                ColumnType::String
+            ))))
+        );
+        assert_eq!(
+            ColumnType::from_type_name("Decimal(38, 18)").unwrap(),
+            ColumnType::Decimal(38, 18)
+        );
+        assert_eq!(
+            ColumnType::from_type_name("Decimal(10)").unwrap(),
+            ColumnType::Decimal(10, 0)
+        );
+        assert_eq!(
+            ColumnType::from_type_name("Decimal64(4)").unwrap(),
+            ColumnType::Decimal(18, 4)
+        );
+        assert_eq!(
+            ColumnType::from_type_name("Nullable(Decimal256(18))").unwrap(),
+            ColumnType::Nullable(Box::new(ColumnType::Decimal(76, 18)))
+        );
+        assert_eq!(
+            ColumnType::from_type_name("DateTime").unwrap(),
+            ColumnType::DateTime(None)
+        );
+        assert_eq!(
+            ColumnType::from_type_name("DateTime('UTC')").unwrap(),
+            ColumnType::DateTime(Some("UTC".into()))
+        );
+        assert_eq!(
+            ColumnType::from_type_name("DateTime64(3)").unwrap(),
+            ColumnType::DateTime64(3, None)
+        );
+        assert_eq!(
+            ColumnType::from_type_name("DateTime64(9, 'America/Sao_Paulo')").unwrap(),
+            ColumnType::DateTime64(9, Some("America/Sao_Paulo".into()))
+        );
+        assert_eq!(
+            ColumnType::from_type_name("Date32").unwrap(),
+            ColumnType::Date32
+        );
+        assert_eq!(
+            ColumnType::from_type_name("Array(Array(Nullable(UInt64)))").unwrap(),
+            ColumnType::Array(Box::new(ColumnType::Array(Box::new(ColumnType::Nullable(
+                Box::new(ColumnType::UInt64)
+            )))))
+        );
+        assert_eq!(
+            ColumnType::from_type_name("Map(String, Array(UInt64))").unwrap(),
+            ColumnType::Map(
+                Box::new(ColumnType::String),
+                Box::new(ColumnType::Array(Box::new(ColumnType::UInt64)))
+            )
+        );
+        assert_eq!(
+            ColumnType::from_type_name("Tuple(UInt8, Decimal(9, 2))").unwrap(),
+            ColumnType::Tuple(vec![
+                (None, ColumnType::UInt8),
+                (None, ColumnType::Decimal(9, 2))
+            ])
+        );
+        assert_eq!(
+            ColumnType::from_type_name("Tuple(amount UInt256, `token` Nullable(String))").unwrap(),
+            ColumnType::Tuple(vec![
+                (Some("amount".into()), ColumnType::UInt256),
+                (
+                    Some("token".into()),
+                    ColumnType::Nullable(Box::new(ColumnType::String))
+                )
+            ])
+        );
+        assert_eq!(
+            ColumnType::from_type_name("UUID").unwrap(),
+            ColumnType::Uuid
+        );
+        assert_eq!(
+            ColumnType::from_type_name("Nullable(IPv6)").unwrap(),
+            ColumnType::Nullable(Box::new(ColumnType::IPv6))
+        );
+        assert_eq!(
+            ColumnType::from_type_name("Enum8('buy' = 1, 'sell' = -1)").unwrap(),
+            ColumnType::Enum8(vec![("buy".into(), 1), ("sell".into(), -1)])
+        );
+        assert_eq!(
+            ColumnType::from_type_name("Enum16('a = b' = 1000)").unwrap(),
+            ColumnType::Enum16(vec![("a = b".into(), 1000)])
+        );
+        assert!(ColumnType::from_type_name("Enum8('a' = 1000)").is_err());
+        assert!(ColumnType::from_type_name("Unsupported").is_err());
+        assert!(ColumnType::from_type_name("Map(String)").is_err());
+        assert!(ColumnType::from_type_name("Array").is_err());
+        assert!(ColumnType::from_type_name("DateTime64").is_err());
+        assert!(ColumnType::from_type_name("DateTime64(3, 'UTC', 1)").is_err());
+        assert!(ColumnType::from_type_name("Decimal(2, 3)").is_err());
+        assert!(ColumnType::from_type_name("Decimal").is_err());
+        assert!(ColumnType::from_type_name("Nullable").is_err());
+        assert!(ColumnType::from_type_name("LowCardinality").is_err());
+        assert!(ColumnType::from_type_name("Unknown(1)").is_err());
+    }
+
+    #[test]
+    fn test_deserialize_unsupported_column_type() {
+        let deserialize = |data_type: &'static str| {
+            let deserializer: StrDeserializer<serde::de::value::Error> =
+                data_type.into_deserializer();
+            ColumnType::deserialize(deserializer).unwrap()
+        };
+        assert_eq!(
+            deserialize("Nullable(String)"),
+            ColumnType::Nullable(Box::new(ColumnType::String))
+        );
+        assert_eq!(
+            deserialize("AggregateFunction(sum, UInt64)"),
+            ColumnType::Unsupported("AggregateFunction(sum, UInt64)".into())
+        );
+    }
+
+    #[test]
+    fn test_hex_decode_options() {
+        let hex_decode = vec!["transfers.from".to_string(), "pools".to_string()];
+        let transfers = ConversionOptions::default().with_hex_decode("transfers", &hex_decode);
+        assert!(transfers.is_hex_column("from"));
+        assert!(!transfers.is_hex_column("to"));
+        let pools = ConversionOptions::default().with_hex_decode("pools", &hex_decode);
+        assert!(pools.is_hex_column("token0"));
+        let swaps = ConversionOptions::default().with_hex_decode("swaps", &hex_decode);
+        assert!(!swaps.is_hex_column("from"));
+    }
+
+    #[test]
+    fn test_display_column_type() {
+        for data_type in [
+            "UInt64",
+            "UUID",
+            "FixedString(20)",
+            "Nullable(Decimal(38, 18))",
+            "LowCardinality(Nullable(String))",
+            "DateTime('UTC')",
+            "DateTime64(3, 'UTC')",
+            "Array(Map(String, Tuple(UInt8, name String)))",
+            "Enum8('buy' = 1, 'sell' = -1)",
+        ] {
+            let column_type = ColumnType::from_type_name(data_type).unwrap();
+            assert_eq!(column_type.to_string(), data_type);
+        }
+    }
+
+    #[test]
+    fn test_parse_inserter_settings() {
+        assert_eq!(
+            parse_table_inserter_settings("transfers=end-timeout=60,max-entries=1000"),
+            Ok((
+                "transfers".to_string(),
+                InserterSettings {
+                    end_timeout: Some(Duration::from_secs(60)),
+                    max_entries: Some(1000),
+                    ..Default::default()
+                }
+            ))
+        );
+        assert!("period".parse::<InserterSettings>().is_err());
+        assert!("period=1s".parse::<InserterSettings>().is_err());
+        assert!("timeout=1".parse::<InserterSettings>().is_err());
+
+        let global = InserterSettings {
+            period: Some(Duration::from_secs(15)),
+            max_bytes: Some(1 << 20),
+            ..Default::default()
+        };
+        let table = InserterSettings {
+            period: Some(Duration::from_secs(1)),
+            ..Default::default()
+        };
+        assert_eq!(
+            global.merge(&table),
+            InserterSettings {
+                period: Some(Duration::from_secs(1)),
+                max_bytes: Some(1 << 20),
+                ..Default::default()
+            }
+        );
+    }
+
+    #[test]
+    fn test_parse_mutation_strategy() {
+        assert_eq!(
+            parse_table_mutation_strategy("pools=replacing:version:is_deleted"),
+            Ok((
+                "pools".to_string(),
+                MutationStrategy::Replacing {
+                    version: "version".into(),
+                    is_deleted: Some("is_deleted".into()),
+                }
+            ))
+        );
+        assert_eq!(
+            "replacing:version".parse(),
+            Ok(MutationStrategy::Replacing {
+                version: "version".into(),
+                is_deleted: None,
+            })
+        );
+        assert_eq!(
+            "collapsing:sign".parse(),
+            Ok(MutationStrategy::Collapsing {
+                sign: "sign".into(),
+                version: None,
+            })
+        );
+        assert_eq!(
+            "collapsing:sign:version".parse(),
+            Ok(MutationStrategy::Collapsing {
+                sign: "sign".into(),
+                version: Some("version".into()),
+            })
+        );
+        assert_eq!(
+            "lightweight-delete".parse(),
+            Ok(MutationStrategy::LightweightDelete)
+        );
+        assert!("replacing".parse::<MutationStrategy>().is_err());
+        assert!("collapsing:sign:version:extra"
+            .parse::<MutationStrategy>()
+            .is_err());
+        assert!(parse_table_mutation_strategy("insert").is_err());
+    }
+
+    #[test]
+    fn test_default_value() {
+        let options = ConversionOptions::default();
+        for data_type in [
+            "UInt64",
+            "Int256",
+            "String",
+            "FixedString(20)",
+            "Decimal(38, 18)",
+            "Nullable(String)",
+            "DateTime64(3, 'UTC')",
+            "Date32",
+            "Array(UInt8)",
+            "Map(String, UInt64)",
+            "Tuple(UInt8, name String)",
+            "UUID",
+            "IPv6",
+            "Enum8('buy' = 1, 'sell' = -1)",
+        ] {
+            let column_type = ColumnType::from_type_name(data_type).unwrap();
+            assert!(
+                column_type.default_value(&options).is_ok(),
+                "no default value for {}",
+                data_type
+            );
+        }
+    }
+
+    #[test]
+    fn test_convert_datetime_timezone() {
+        let options = ConversionOptions::default();
+        let convert = |data_type: &str, value: &str| {
+            ColumnType::from_type_name(data_type)
+                .unwrap()
+                .convert(Some(value), &options, false)
+        };
+        assert_eq!(
+            convert("DateTime", "2023-08-04 13:53:29"),
+            Ok(Value::UInt32(1691157209))
+        );
+        assert_eq!(
+            convert("DateTime('America/New_York')", "2023-08-04 09:53:29"),
+            Ok(Value::UInt32(1691157209))
+        );
+        assert_eq!(
+            convert("DateTime('America/New_York')", "2023-08-04T13:53:29Z"),
+            Ok(Value::UInt32(1691157209))
+        );
+        assert_eq!(
+            convert("DateTime64(3, 'Asia/Tokyo')", "2023-08-04 22:53:29.123"),
+            Ok(Value::Int64(1691157209123))
+        );
+    }
+
+    #[test]
+    fn test_key_condition() {
+        let table = DynamicTable::new(
+            "pools",
+            vec![
+                ColumnInfo {
+                    column_name: "address".into(),
+                    data_type: ColumnType::FixedString(20),
+                },
+                ColumnInfo {
+                    column_name: "token_id".into(),
+                    data_type: ColumnType::UInt256,
+                },
+                ColumnInfo {
+                    column_name: "name".into(),
+                    data_type: ColumnType::String,
+                },
+            ],
+        )
+        .with_options(ConversionOptions::default().with_hex_decode("pools", &["pools".into()]));
+        let keys = HashMap::from([
+            ("address".to_string(), "0x0102".to_string()),
+            ("token_id".to_string(), "0xff".to_string()),
+        ]);
+        assert_eq!(
+            table.key_condition(&keys).unwrap(),
+            "`address` = unhex('0102000000000000000000000000000000000000') \
+            AND `token_id` = 255"
+        );
+        let keys = HashMap::from([("address".to_string(), "0xzz".to_string())]);
+        assert_eq!(table.key_condition(&keys).unwrap_err().column, "address");
+
+        let table = DynamicTable::new(
+            "names",
+            vec![ColumnInfo {
+                column_name: "name".into(),
+                data_type: ColumnType::String,
+            }],
+        );
+        let keys = HashMap::from([("name".to_string(), "a'?".to_string())]);
+        assert_eq!(
+            table.key_condition(&keys).unwrap(),
+            "`name` = unhex('61273f')"
+        );
+    }
+
+    #[test]
+    fn test_sql_literal() {
+        let options = ConversionOptions::default();
+        for (data_type, value, literal) in [
+            ("Int256", "-2", "-2"),
+            ("Decimal(9, 2)", "-1.05", "toDecimal256('-1.05', 2)"),
+            ("Decimal(76, 3)", "0.5", "toDecimal256('0.500', 3)"),
+            ("Nullable(UInt8)", "1", "1"),
+            ("Nullable(UInt8)", "null", "NULL"),
+            (
+                "UUID",
+                "{61f0c404-5cb3-11e7-907b-a6006ad3dba0}",
+                "'61f0c404-5cb3-11e7-907b-a6006ad3dba0'",
+            ),
+            ("IPv6", "::1", "'::1'"),
+            ("Enum8('buy' = 1, 'sell' = -1)", "-1", "'sell'"),
+            ("DateTime", "1691157209", "toDateTime(1691157209, 'UTC')"),
+            (
+                "DateTime64(3)",
+                "1691157209123",
+                "fromUnixTimestamp64Nano(1691157209123000000, 'UTC')",
+            ),
+            ("Date", "1970-01-02", "addDays(toDate('1970-01-01'), 1)"),
+            ("Array(UInt8)", "[1, 2]", "[1, 2]"),
+        ] {
+            let column_type = ColumnType::from_type_name(data_type).unwrap();
+            let value = column_type.convert(Some(value), &options, false).unwrap();
+            assert_eq!(column_type.sql_literal(&value), literal, "{}", data_type);
+        }
+    }
+
+    #[test]
+    fn test_key_column() {
+        let table_info = |primary_key: &str| TableInfo {
+            table_schema: "default".into(),
+            table_name: "pools".into(),
+            primary_key: primary_key.into(),
+        };
+        assert_eq!(table_info("id").key_column(), Some("id".into()));
+        assert_eq!(table_info("`id`").key_column(), Some("id".into()));
+        assert_eq!(table_info("chain, id").key_column(), None);
+        assert_eq!(table_info("toDate(timestamp)").key_column(), None);
+        assert_eq!(table_info("").key_column(), None);
+        assert_eq!(
+            parse_table_key_column("pools=address"),
+            Ok(("pools".into(), "address".into()))
+        );
+        assert!(parse_table_key_column("pools").is_err());
+    }
+
+    #[test]
+    fn test_quote_identifier() {
+        assert_eq!(quote_identifier("pools"), "`pools`");
+        assert_eq!(
+            quote_identifier("pools`; DROP TABLE cursors; --"),
+            r"`pools\`; DROP TABLE cursors; --`"
+        );
+        assert_eq!(quote_identifier(r"a\`b"), r"`a\\\`b`");
+    }
+
+    #[tokio::test]
+    async fn test_get_columns_hostile_table() {
+        let mock = test::Mock::new();
+        let client = Client::default().with_url(mock.url());
+        let recording = mock.add(test::handlers::record_ddl());
+        let columns = get_columns(&client, "default", "pools' OR '1'='1")
+            .await
+            .unwrap();
+        assert!(columns.is_empty());
+        let query = recording.query().await;
+        assert!(query.contains(r"table_schema = 'default'"));
+        assert!(query.contains(r"table_name = 'pools\' OR \'1\'=\'1'"));
+    }
+}
@@ -0,0 +1,419 @@
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    fmt::Display,
+};
+
+use futures03::StreamExt;
+use prost::Message;
+use substreams_database_change::pb::database::DatabaseChanges;
+
+use crate::{
+    loader::{change_mutations, split_table_changes, Mutation},
+    pb::sf::substreams::v1::Package,
+    substreams_stream::{BlockResponse, SubstreamsStream},
+    table_info::{DynamicInsert, DynamicTable},
+    ElricError,
+};
+
+/// Output type of the modules that can be loaded
+const DATABASE_CHANGES_TYPE: &str = "sf.substreams.sink.database.v1.DatabaseChanges";
+
+/// A value of a sampled change that doesn't fit its column
+#[derive(Debug, PartialEq)]
+pub struct InvalidValue {
+    pub block_num: u64,
+    pub value: Option<String>,
+    pub data_type: String,
+    pub error: String,
+}
+
+/// Differences between the output of a module and the ClickHouse tables
+#[derive(Debug, Default)]
+pub struct ValidationReport {
+    /// Problems with the module itself, e.g. a missing module or output type
+    pub module_errors: Vec<String>,
+    /// Number of sampled blocks
+    pub blocks: u64,
+    /// Tables written by the module that don't exist, with their number of changes
+    pub missing_tables: BTreeMap<String, u64>,
+    /// Fields written by the module that are not a column of their table
+    pub missing_columns: BTreeMap<String, BTreeSet<String>>,
+    /// Columns of the written tables that no sampled change gives a value to
+    pub empty_columns: BTreeMap<String, BTreeSet<String>>,
+    /// First value of each column that could not be converted
+    pub invalid_values: BTreeMap<(String, String), InvalidValue>,
+}
+
+impl ValidationReport {
+    /// Whether the module can be loaded, columns without data being only reported
+    pub fn is_compatible(&self) -> bool {
+        self.module_errors.is_empty()
+            && self.missing_tables.is_empty()
+            && self.missing_columns.is_empty()
+            && self.invalid_values.is_empty()
+    }
+}
+
+impl Display for ValidationReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for error in &self.module_errors {
+            writeln!(f, "error: {}", error)?;
+        }
+        for (table, changes) in &self.missing_tables {
+            writeln!(f, "error: missing table {} ({} changes)", table, changes)?;
+        }
+        for (table, columns) in &self.missing_columns {
+            for column in columns {
+                writeln!(f, "error: missing column {}.{}", table, column)?;
+            }
+        }
+        for ((table, column), invalid) in &self.invalid_values {
+            writeln!(
+                f,
+                "error: invalid value {:?} for {}.{} ({}) at block {}: {}",
+                invalid.value.as_deref().unwrap_or_default(),
+                table,
+                column,
+                invalid.data_type,
+                invalid.block_num,
+                invalid.error
+            )?;
+        }
+        for (table, columns) in &self.empty_columns {
+            for column in columns {
+                writeln!(f, "warning: no data for column {}.{}", table, column)?;
+            }
+        }
+        let result = if self.is_compatible() {
+            "compatible"
+        } else {
+            "incompatible"
+        };
+        write!(f, "{} blocks sampled, {}", self.blocks, result)
+    }
+}
+
+/// Checks the output of a module against the tables it is loaded into
+pub struct Validator {
+    tables: HashMap<String, DynamicTable>,
+    /// Columns given a value by the sampled changes, by table
+    columns_with_data: HashMap<String, BTreeSet<String>>,
+    report: ValidationReport,
+}
+
+impl Validator {
+    pub fn new(tables: Vec<DynamicTable>) -> Self {
+        Self {
+            tables: tables
+                .into_iter()
+                .map(|table| (table.table_name.clone(), table))
+                .collect(),
+            columns_with_data: HashMap::new(),
+            report: ValidationReport::default(),
+        }
+    }
+
+    /// Checks that the package has the module and that it outputs `DatabaseChanges`,
+    /// as described by the proto descriptors of the package
+    pub fn check_module(&mut self, package: &Package, module: &str) {
+        let output_type = package
+            .modules
+            .iter()
+            .flat_map(|modules| &modules.modules)
+            .find(|m| m.name == module)
+            .map(|m| {
+                m.output
+                    .as_ref()
+                    .map(|o| o.r#type.as_str())
+                    .unwrap_or_default()
+            });
+        let Some(output_type) = output_type else {
+            let error = format!("module {} not found in package", module);
+            self.report.module_errors.push(error);
+            return;
+        };
+        let output_type = output_type.trim_start_matches("proto:");
+        if output_type != DATABASE_CHANGES_TYPE {
+            self.report.module_errors.push(format!(
+                "module {} outputs {}, expected {}",
+                module, output_type, DATABASE_CHANGES_TYPE
+            ));
+            return;
+        }
+        let (proto_package, message) = DATABASE_CHANGES_TYPE.rsplit_once('.').unwrap();
+        let has_descriptor = package
+            .proto_files
+            .iter()
+            .filter(|file| file.package() == proto_package)
+            .flat_map(|file| &file.message_type)
+            .any(|descriptor| descriptor.name() == message);
+        if !has_descriptor {
+            let error = format!("package has no descriptor for {}", DATABASE_CHANGES_TYPE);
+            self.report.module_errors.push(error);
+        }
+    }
+
+    /// Checks the changes of a block, converting them as they would be loaded
+    pub fn check_block(&mut self, block_num: u64, database_changes: DatabaseChanges) {
+        self.report.blocks += 1;
+        for (table, changes) in split_table_changes(database_changes.table_changes) {
+            let Some(table_info) = self.tables.get(&table) else {
+                *self.report.missing_tables.entry(table).or_default() += changes.len() as u64;
+                continue;
+            };
+            for change in changes {
+                let missing_columns = change
+                    .fields
+                    .iter()
+                    .filter(|field| !table_info.has_column(&field.name))
+                    .map(|field| field.name.clone())
+                    .collect::<BTreeSet<_>>();
+                if !missing_columns.is_empty() {
+                    self.report
+                        .missing_columns
+                        .entry(table.clone())
+                        .or_default()
+                        .extend(missing_columns);
+                }
+
+                let mutations = change_mutations(
+                    table_info.mutation_strategy(),
+                    table_info.key_column(),
+                    change,
+                    block_num,
+                );
+                for mutation in mutations {
+                    let (fields, conversion) = match mutation {
+                        Mutation::Insert(fields) => {
+                            let conversion = DynamicInsert::new(table_info, &fields).map(|_| ());
+                            (fields, conversion)
+                        }
+                        Mutation::InsertTombstone(fields) => {
+                            let conversion =
+                                DynamicInsert::with_defaults(table_info, &fields).map(|_| ());
+                            (fields, conversion)
+                        }
+                        Mutation::Delete(keys) => {
+                            let conversion = table_info.key_condition(&keys).map(|_| ());
+                            (keys, conversion)
+                        }
+                    };
+                    self.columns_with_data
+                        .entry(table.clone())
+                        .or_default()
+                        .extend(
+                            fields
+                                .into_iter()
+                                .filter(|(_, value)| !value.is_empty())
+                                .map(|(column, _)| column),
+                        );
+                    if let Err(e) = conversion {
+                        self.report
+                            .invalid_values
+                            .entry((table.clone(), e.column))
+                            .or_insert(InvalidValue {
+                                block_num,
+                                value: e.value,
+                                data_type: e.data_type.to_string(),
+                                error: e.source.to_string(),
+                            });
+                    }
+                }
+            }
+        }
+    }
+
+    /// Checks the first `blocks` blocks of a stream
+    pub async fn check_stream(
+        &mut self,
+        mut stream: SubstreamsStream,
+        blocks: u64,
+    ) -> Result<(), ElricError> {
+        let mut sampled = 0;
+        while sampled < blocks {
+            match stream.next().await {
+                None => break,
+                Some(Ok(BlockResponse::New(data))) => {
+                    let block_num = data.clock.as_ref().unwrap().number;
+                    let output = data.output.as_ref().unwrap().map_output.as_ref().unwrap();
+                    let database_changes = DatabaseChanges::decode(output.value.as_slice())?;
+                    self.check_block(block_num, database_changes);
+                    sampled += 1;
+                }
+                Some(Ok(BlockResponse::Undo(_))) => {}
+                Some(Err(err)) => return Err(ElricError::StreamError(err)),
+            }
+        }
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> ValidationReport {
+        for (table, columns_with_data) in self.columns_with_data {
+            let empty_columns = self.tables[&table]
+                .columns()
+                .iter()
+                .map(|column| &column.column_name)
+                .filter(|column| !columns_with_data.contains(*column))
+                .cloned()
+                .collect::<BTreeSet<_>>();
+            if !empty_columns.is_empty() {
+                self.report.empty_columns.insert(table, empty_columns);
+            }
+        }
+        self.report
+    }
+}
+
+/// Checks a module against the tables, on the first `blocks` blocks of its output
+pub async fn validate_module(
+    package: &Package,
+    module: &str,
+    tables: Vec<DynamicTable>,
+    stream: SubstreamsStream,
+    blocks: u64,
+) -> Result<ValidationReport, ElricError> {
+    let mut validator = Validator::new(tables);
+    validator.check_module(package, module);
+    if validator.report.module_errors.is_empty() {
+        validator.check_stream(stream, blocks).await?;
+    }
+    Ok(validator.finish())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+
+    use prost_types::{DescriptorProto, FileDescriptorProto};
+    use substreams_database_change::pb::database::{DatabaseChanges, Field, TableChange};
+
+    use crate::{
+        pb::sf::substreams::v1::{
+            module::{self, Output},
+            Module, Modules, Package,
+        },
+        table_info::{ColumnInfo, ColumnType, DynamicTable},
+    };
+
+    use super::{Validator, DATABASE_CHANGES_TYPE};
+
+    fn create_validator() -> Validator {
+        Validator::new(vec![DynamicTable::new(
+            "test",
+            vec![
+                ColumnInfo {
+                    column_name: "amount".into(),
+                    data_type: ColumnType::UInt64,
+                },
+                ColumnInfo {
+                    column_name: "name".into(),
+                    data_type: ColumnType::Nullable(Box::new(ColumnType::String)),
+                },
+            ],
+        )])
+    }
+
+    fn create_package(output_type: &str) -> Package {
+        Package {
+            proto_files: vec![FileDescriptorProto {
+                package: Some("sf.substreams.sink.database.v1".into()),
+                message_type: vec![DescriptorProto {
+                    name: Some("DatabaseChanges".into()),
+                    ..Default::default()
+                }],
+                ..Default::default()
+            }],
+            modules: Some(Modules {
+                modules: vec![Module {
+                    name: "db_out".into(),
+                    output: Some(Output {
+                        r#type: output_type.into(),
+                    }),
+                    kind: Some(module::Kind::KindMap(module::KindMap {
+                        output_type: output_type.into(),
+                    })),
+                    ..Default::default()
+                }],
+                ..Default::default()
+            }),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_check_module() {
+        let package = create_package(&format!("proto:{}", DATABASE_CHANGES_TYPE));
+        let mut validator = create_validator();
+        validator.check_module(&package, "db_out");
+        assert!(validator.finish().is_compatible());
+
+        let mut validator = create_validator();
+        validator.check_module(&package, "map_events");
+        assert!(!validator.finish().is_compatible());
+
+        let package = create_package("proto:sf.ethereum.type.v2.Block");
+        let mut validator = create_validator();
+        validator.check_module(&package, "db_out");
+        let report = validator.finish();
+        assert_eq!(
+            report.module_errors,
+            vec![format!(
+                "module db_out outputs sf.ethereum.type.v2.Block, expected {}",
+                DATABASE_CHANGES_TYPE
+            )]
+        );
+    }
+
+    #[test]
+    fn test_check_block() {
+        let change = |table: &str, fields: &[(&str, &str)]| TableChange {
+            table: table.into(),
+            fields: fields
+                .iter()
+                .map(|(name, value)| Field {
+                    name: name.to_string(),
+                    new_value: value.to_string(),
+                    ..Default::default()
+                })
+                .collect(),
+            ..Default::default()
+        };
+        let mut validator = create_validator();
+        validator.check_block(
+            1,
+            DatabaseChanges {
+                table_changes: vec![
+                    change("test", &[("amount", "1")]),
+                    change("test", &[("amount", "1.5"), ("symbol", "ETH")]),
+                    change("other", &[("amount", "1")]),
+                ],
+            },
+        );
+        let report = validator.finish();
+        assert!(!report.is_compatible());
+        assert_eq!(report.blocks, 1);
+        assert_eq!(report.missing_tables.get("other"), Some(&1));
+        assert_eq!(
+            report.missing_columns.get("test").unwrap(),
+            &BTreeSet::from(["symbol".to_string()])
+        );
+        assert_eq!(
+            report.empty_columns.get("test").unwrap(),
+            &BTreeSet::from(["name".to_string()])
+        );
+        assert_eq!(report.invalid_values.len(), 1);
+        let invalid = &report.invalid_values[&("test".to_string(), "amount".to_string())];
+        assert_eq!(invalid.block_num, 1);
+        assert_eq!(invalid.value.as_deref(), Some("1.5"));
+        assert_eq!(invalid.data_type, "UInt64");
+
+        let mut validator = create_validator();
+        validator.check_block(
+            1,
+            DatabaseChanges {
+                table_changes: vec![change("test", &[("amount", "1"), ("name", "a")])],
+            },
+        );
+        assert!(validator.finish().is_compatible());
+    }
+}